@@ -0,0 +1,181 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::ffi::OsStr;
+use std::fs::{create_dir, File};
+use std::io::{copy, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+
+use super::clean::delete_items;
+use super::idevsutil::Transfer;
+use super::{
+    context, format_size, make_arg, new_temp_dir, new_temp_file, remote_home,
+    run_transfer_streaming, Config, Fallible,
+};
+
+// Small files are uploaded separately to estimate the fixed cost per file,
+// which is then subtracted to estimate the throughput of the link itself.
+pub fn benchmark(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    files: usize,
+    file_size: u64,
+) -> Fallible {
+    info!(
+        "Benchmarking uploads of {} files of {} bytes to {} ({}) at {}...",
+        files, file_size, config.device_name, dev_id, srv_ip
+    );
+
+    let scratch_dir = format!(
+        ".idrive_backup_benchmark_{}_{}",
+        process::id(),
+        Local::now().timestamp()
+    );
+
+    let res = run_benchmark(config, srv_ip, dev_id, &scratch_dir, files, file_size);
+
+    if !config.print_args {
+        info!("Removing scratch directory /{scratch_dir} from archive...");
+    }
+
+    delete_items(
+        config,
+        srv_ip,
+        dev_id,
+        false,
+        &[Path::new("/").join(&scratch_dir)],
+    )
+    .map_err(context("Failed to remove scratch directory"))?;
+
+    res
+}
+
+fn run_benchmark(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    scratch_dir: &str,
+    files: usize,
+    file_size: u64,
+) -> Fallible {
+    let small_elapsed = upload_random_files(config, srv_ip, dev_id, scratch_dir, "small", files, 1)
+        .map_err(context("Failed to upload small files"))?;
+
+    let large_elapsed = upload_random_files(
+        config,
+        srv_ip,
+        dev_id,
+        scratch_dir,
+        "large",
+        files,
+        file_size,
+    )
+    .map_err(context("Failed to upload large files"))?;
+
+    if config.print_args {
+        return Ok(());
+    }
+
+    let total_size = files as u64 * file_size;
+    let overhead = small_elapsed / files.max(1) as u32;
+    let transfer_secs = large_elapsed.saturating_sub(small_elapsed).as_secs_f64();
+
+    let (size, unit) = format_size(total_size, config.size_units);
+
+    info!(
+        "Uploaded {:.1} {} in {:.1} s",
+        size,
+        unit,
+        large_elapsed.as_secs_f64()
+    );
+
+    if transfer_secs > 0.0 {
+        let rate = total_size as f64 / transfer_secs;
+
+        info!(
+            "Throughput: {:.1} MB/s excluding per-file overhead, {:.1} MB/s overall",
+            rate / 1e6,
+            total_size as f64 / large_elapsed.as_secs_f64() / 1e6
+        );
+    } else {
+        warn!("Throughput could not be determined, please increase the number or size of files");
+    }
+
+    info!(
+        "Per-file overhead: {:.0} ms",
+        overhead.as_secs_f64() * 1000.0
+    );
+
+    Ok(())
+}
+
+// Random contents make sure that deduplication cannot skip any transfers.
+fn upload_random_files(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    scratch_dir: &str,
+    prefix: &str,
+    files: usize,
+    file_size: u64,
+) -> Fallible<Duration> {
+    let temp_dir = new_temp_dir()?;
+    create_dir(temp_dir.path().join(scratch_dir))?;
+
+    let list_file = new_temp_file()?;
+
+    {
+        let mut list_file = BufWriter::new(list_file.as_file());
+        let mut random = File::open("/dev/urandom")?;
+
+        for index in 0..files {
+            let rel_path = PathBuf::from(scratch_dir).join(format!("{prefix}_{index}"));
+
+            let mut file = File::create(temp_dir.path().join(&rel_path))?;
+            copy(&mut (&mut random).take(file_size), &mut file)?;
+            file.flush()?;
+
+            list_file.write_all(rel_path.as_os_str().as_bytes())?;
+            list_file.write_all(b"\n")?;
+        }
+    }
+
+    let start = Instant::now();
+
+    run_transfer_streaming(
+        config,
+        [
+            OsStr::new("--xml-output"),
+            OsStr::new("--type"),
+            &make_arg("--device-id=", dev_id),
+            &make_arg("--files-from=", list_file.path()),
+            OsStr::new("--relative"),
+            temp_dir.path().as_os_str(),
+            &remote_home(config, srv_ip),
+        ],
+        |_transfer: Transfer| Ok(()),
+    )?;
+
+    Ok(start.elapsed())
+}
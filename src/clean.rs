@@ -16,67 +16,422 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
 */
-use std::ffi::{OsStr, OsString};
-use std::io::{BufWriter, Write};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::str::from_utf8;
 
-use serde::Deserialize;
-use tempfile::NamedTempFile;
+use chrono::Local;
+use serde::Serialize;
+use serde_json::to_string as to_json_string;
 
-use super::{context, make_arg, parse_items, run_util, walk_dir, Config, Fallible};
+use super::idevsutil::Operation;
+use super::interrupt::interrupted;
+use super::{
+    context, find_exclude, format_size, local_path, make_arg, new_temp_file, remote_home,
+    run_util_streaming, walk_dir, Config, Fallible,
+};
 
-pub fn clean(config: &Config, srv_ip: &str, dev_id: &str, dry_run: bool) -> Fallible {
-    eprintln!(
+#[derive(Default)]
+pub struct Options {
+    pub dry_run: bool,
+    pub plan: Option<PathBuf>,
+    pub apply: Option<PathBuf>,
+    pub min_age: Option<u64>,
+}
+
+pub fn clean(config: &Config, srv_ip: &str, dev_id: &str, opts: &Options) -> Fallible {
+    if let Some(plan) = &opts.plan {
+        return write_plan(config, srv_ip, dev_id, opts, plan);
+    }
+
+    if let Some(plan) = &opts.apply {
+        return apply_plan(config, srv_ip, dev_id, opts, plan);
+    }
+
+    info!(
         "Cleaning archive of {} ({}) at {}...",
         config.device_name, dev_id, srv_ip
     );
 
+    let dry_run = opts.dry_run;
+
     let mut items = Vec::new();
+    let mut items_size = 0;
+    let mut freed_size = 0;
+    let mut deleted = Vec::new();
+    let mut deletions = Deletions::default();
+    let mut min_age = MinAge::new(opts.min_age);
+
+    walk_dir(
+        config,
+        srv_ip,
+        dev_id,
+        Path::new("/"),
+        |path, size, mtime| {
+            if keep_item(config, &mut min_age, &path, size, mtime) {
+                Ok(Some(path))
+            } else {
+                items.push(path);
+                items_size += size.unwrap_or(0);
+
+                if items.len() == config.clean_batch_size.max(1) {
+                    deletions.delete(config, srv_ip, dev_id, dry_run, &items)?;
 
-    walk_dir(config, srv_ip, dev_id, Path::new("/"), |path| {
-        if exists_and_not_excluded(config, &path) {
-            Ok(Some(path))
-        } else {
-            items.push(path);
+                    if config.json {
+                        deleted.append(&mut items);
+                    }
 
-            if items.len() == 100 {
-                delete_items(config, srv_ip, dev_id, dry_run, &items)
-                    .map_err(context("Failed to delete items"))?;
+                    items.clear();
+                    freed_size += items_size;
+                    items_size = 0;
+                }
 
-                items.clear();
+                Ok(None)
             }
+        },
+    )?;
 
-            Ok(None)
+    if !items.is_empty() && !interrupted() {
+        deletions.delete(config, srv_ip, dev_id, dry_run, &items)?;
+
+        freed_size += items_size;
+        deleted.append(&mut items);
+    }
+
+    if let Some(min_age) = &min_age {
+        min_age.report();
+    }
+
+    report_freed(config, dry_run, freed_size, &deletions, &deleted)
+}
+
+// Items missing locally are only deleted once their archived copy is old enough,
+// so that e.g. a drive which is temporarily not mounted does not have its backup deleted.
+struct MinAge {
+    days: u64,
+    cutoff: i64,
+    kept: usize,
+}
+
+impl MinAge {
+    fn new(days: Option<u64>) -> Option<Self> {
+        let days = days?;
+
+        Some(Self {
+            days,
+            cutoff: Local::now().timestamp() - (days * 24 * 60 * 60) as i64,
+            kept: 0,
+        })
+    }
+
+    // Directories are descended into instead of being deleted as a whole,
+    // so that the age of each of their files is checked individually.
+    // Files without a modification time are kept as their age is unknown.
+    fn keeps(&mut self, size: Option<u64>, mtime: Option<i64>) -> bool {
+        if size.is_none() {
+            return true;
+        }
+
+        if mtime.is_some_and(|mtime| mtime < self.cutoff) {
+            return false;
         }
-    })?;
 
-    if !items.is_empty() {
-        delete_items(config, srv_ip, dev_id, dry_run, &items)
-            .map_err(context("Failed to delete items"))?;
+        self.kept += 1;
+        true
+    }
+
+    fn report(&self) {
+        if self.kept != 0 {
+            info!(
+                "Kept {} files missing locally which are not known to be older than {} days.",
+                self.kept, self.days
+            );
+        }
+    }
+}
+
+// Batches are numbered so that a partial failure can be traced back to the items already deleted.
+#[derive(Default)]
+struct Deletions {
+    batches: usize,
+    items: usize,
+}
+
+impl Deletions {
+    fn delete(
+        &mut self,
+        config: &Config,
+        srv_ip: &str,
+        dev_id: &str,
+        dry_run: bool,
+        items: &[PathBuf],
+    ) -> Fallible {
+        delete_items(config, srv_ip, dev_id, dry_run, items).map_err(|err| {
+            format!(
+                "Failed to delete batch {} of {} items after deleting {} items: {err}",
+                self.batches + 1,
+                items.len(),
+                self.items
+            )
+        })?;
+
+        self.batches += 1;
+        self.items += items.len();
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct CleanJson<'a> {
+    dry_run: bool,
+    deleted_items: usize,
+    batches: usize,
+    freed_bytes: u64,
+    items: &'a [PathBuf],
+}
+
+fn report_freed(
+    config: &Config,
+    dry_run: bool,
+    freed_size: u64,
+    deletions: &Deletions,
+    deleted: &[PathBuf],
+) -> Fallible {
+    let (size, unit) = format_size(freed_size, config.size_units);
+
+    if dry_run {
+        info!(
+            "Would have deleted {} items across {} batches, freeing {size:.1} {unit} during clean.",
+            deletions.items, deletions.batches
+        );
+    } else {
+        info!(
+            "Deleted {} items across {} batches, freeing {size:.1} {unit} during clean.",
+            deletions.items, deletions.batches
+        );
+    }
+
+    if config.json {
+        let clean_json = CleanJson {
+            dry_run,
+            deleted_items: deletions.items,
+            batches: deletions.batches,
+            freed_bytes: freed_size,
+            items: deleted,
+        };
+
+        println!("{}", to_json_string(&clean_json)?);
     }
 
     Ok(())
 }
 
+// Items are only collected here, deletion being deferred until the plan is applied.
+fn planned_items(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    opts: &Options,
+) -> Fallible<BTreeMap<PathBuf, Option<u64>>> {
+    let mut items = BTreeMap::new();
+    let mut min_age = MinAge::new(opts.min_age);
+
+    walk_dir(
+        config,
+        srv_ip,
+        dev_id,
+        Path::new("/"),
+        |path, size, mtime| {
+            if keep_item(config, &mut min_age, &path, size, mtime) {
+                Ok(Some(path))
+            } else {
+                items.insert(path, size);
+
+                Ok(None)
+            }
+        },
+    )?;
+
+    if let Some(min_age) = &min_age {
+        min_age.report();
+    }
+
+    Ok(items)
+}
+
+fn write_plan(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    opts: &Options,
+    plan: &Path,
+) -> Fallible {
+    info!(
+        "Planning clean of archive of {} ({}) at {}...",
+        config.device_name, dev_id, srv_ip
+    );
+
+    let items = planned_items(config, srv_ip, dev_id, opts)?;
+
+    let mut writer = BufWriter::new(File::create(plan)?);
+
+    writeln!(
+        writer,
+        "# Clean plan for {} ({}) created at {}",
+        config.device_name,
+        dev_id,
+        Local::now()
+    )?;
+
+    for (path, size) in &items {
+        match size {
+            Some(size) => write!(writer, "{size}\t")?,
+            None => write!(writer, "-\t")?,
+        }
+
+        writer.write_all(path.as_os_str().as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+
+    writer.flush()?;
+
+    let (size, unit) = format_size(items.values().flatten().sum(), config.size_units);
+
+    info!(
+        "Planned deletion of {} items freeing {:.1} {} in {}",
+        items.len(),
+        size,
+        unit,
+        plan.display()
+    );
+
+    Ok(())
+}
+
+// Refuses to delete anything if any planned item would no longer be deleted
+// or has changed in size since the plan was written.
+fn apply_plan(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    opts: &Options,
+    plan: &Path,
+) -> Fallible {
+    info!(
+        "Applying clean plan {} to archive of {} ({}) at {}...",
+        plan.display(),
+        config.device_name,
+        dev_id,
+        srv_ip
+    );
+
+    let dry_run = opts.dry_run;
+
+    let planned = read_plan(plan).map_err(context("Failed to read clean plan"))?;
+    let current = planned_items(config, srv_ip, dev_id, opts)?;
+
+    let mut changed = 0;
+
+    for (path, size) in &planned {
+        match current.get(path) {
+            Some(current_size) if current_size == size => (),
+            Some(_) => {
+                warn!("Planned item {} has changed in size", path.display());
+                changed += 1;
+            }
+            None => {
+                warn!(
+                    "Planned item {} is no longer eligible for deletion",
+                    path.display()
+                );
+                changed += 1;
+            }
+        }
+    }
+
+    if changed != 0 {
+        return Err(format!(
+            "{changed} of {} planned items have changed since planning, please plan again",
+            planned.len()
+        )
+        .into());
+    }
+
+    let items = planned.keys().cloned().collect::<Vec<_>>();
+
+    let mut deletions = Deletions::default();
+
+    for items in items.chunks(config.clean_batch_size.max(1)) {
+        deletions.delete(config, srv_ip, dev_id, dry_run, items)?;
+    }
+
+    report_freed(
+        config,
+        dry_run,
+        planned.values().flatten().sum(),
+        &deletions,
+        &items,
+    )
+}
+
+fn read_plan(plan: &Path) -> Fallible<BTreeMap<PathBuf, Option<u64>>> {
+    let mut items = BTreeMap::new();
+
+    for line in BufReader::new(File::open(plan)?).split(b'\n') {
+        let line = line?;
+
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+
+        let (size, path) = match line.iter().position(|byte| *byte == b'\t') {
+            Some(pos) => (&line[..pos], &line[pos + 1..]),
+            None => return Err("Malformed line in clean plan".into()),
+        };
+
+        let size = match size {
+            b"-" => None,
+            size => Some(from_utf8(size)?.parse()?),
+        };
+
+        items.insert(PathBuf::from(OsStr::from_bytes(path)), size);
+    }
+
+    Ok(items)
+}
+
+fn keep_item(
+    config: &Config,
+    min_age: &mut Option<MinAge>,
+    path: &Path,
+    size: Option<u64>,
+    mtime: Option<i64>,
+) -> bool {
+    exists_and_not_excluded(config, path)
+        || min_age
+            .as_mut()
+            .is_some_and(|min_age| min_age.keeps(size, mtime))
+}
+
 fn exists_and_not_excluded(config: &Config, path: &Path) -> bool {
-    let path = match path.canonicalize() {
+    let path = match local_path(config, path).canonicalize() {
         Ok(path) => path,
         Err(_) => return false,
     };
 
-    if config
-        .excludes
-        .iter()
-        .any(|exclude| path.starts_with(exclude))
-    {
+    if find_exclude(config, &path).is_some() {
         return false;
     }
 
     true
 }
 
-fn delete_items(
+pub fn delete_items(
     config: &Config,
     srv_ip: &str,
     dev_id: &str,
@@ -84,14 +439,14 @@ fn delete_items(
     items: &[PathBuf],
 ) -> Fallible {
     for item in items {
-        eprintln!("Deleting item {} from archive", item.display());
+        debug!("Deleting item {} from archive", item.display());
     }
 
     if dry_run {
         return Ok(());
     }
 
-    let list_file = NamedTempFile::new()?;
+    let list_file = new_temp_file()?;
     let mut item_cnt = 0;
 
     {
@@ -105,7 +460,9 @@ fn delete_items(
         }
     }
 
-    let output = run_util(
+    let mut items_deleted = None;
+
+    run_util_streaming(
         config,
         [
             OsStr::new("--delete-items"),
@@ -113,28 +470,54 @@ fn delete_items(
             &make_arg("--files-from=", list_file.path()),
             OsStr::new("--relative"),
             &make_arg("--device-id=", dev_id),
-            &OsString::from(format!("{}@{}::home/", config.username, srv_ip)),
+            &remote_home(config, srv_ip),
         ],
+        |operation: Operation| {
+            if items_deleted.is_none() {
+                items_deleted = operation.items_deleted;
+            }
+
+            Ok(())
+        },
     )?;
 
-    #[derive(Deserialize)]
-    #[serde(rename = "item")]
-    struct Operation {
-        #[serde(rename = "tot_items_deleted")]
-        items_deleted: Option<usize>,
+    match items_deleted {
+        _ if config.print_args => Ok(()),
+        Some(items_deleted) if items_deleted == item_cnt => Ok(()),
+        Some(items_deleted) => {
+            Err(format!("Deleted only {items_deleted} of {item_cnt} items").into())
+        }
+        None => Err(format!("Deletion of {item_cnt} items was not confirmed").into()),
     }
+}
 
-    let operations = parse_items::<Operation>(output)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for operation in operations {
-        if let Some(items_deleted) = operation.items_deleted {
-            if items_deleted == item_cnt {
-                return Ok(());
-            } else {
-                return Err(format!("Deleted only {items_deleted} of {item_cnt} items").into());
-            }
-        }
-    }
+    use std::fs::{create_dir_all, write};
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
 
-    Err(format!("Deletion of {item_cnt} items was not confirmed").into())
+    use super::super::test_util::loaded_config;
+
+    #[test]
+    fn exists_and_not_excluded_matches_excludes_through_symbolic_links() {
+        let dir = TempDir::new().unwrap();
+        let data = dir.path().canonicalize().unwrap().join("data");
+        create_dir_all(data.join("cache")).unwrap();
+        write(data.join("cache/file"), "contents").unwrap();
+        write(data.join("file"), "contents").unwrap();
+        symlink(&data, dir.path().join("link")).unwrap();
+
+        let config = loaded_config(&format!(
+            "includes: []\nexcludes: [{}/link/cache/]",
+            dir.path().display()
+        ));
+
+        assert!(!exists_and_not_excluded(&config, &data.join("cache/file")));
+        assert!(exists_and_not_excluded(&config, &data.join("file")));
+        assert!(!exists_and_not_excluded(&config, &data.join("missing")));
+    }
 }
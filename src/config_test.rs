@@ -0,0 +1,250 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::daemon::Schedule;
+use super::hash::from_hex;
+use super::{parse_duration, Config, Fallible};
+
+// Only inspects the configuration and the local file system, so that it can run
+// in pipelines without credentials, idevsutil_dedup or network access.
+pub fn config_test(config: &Config) -> Fallible {
+    let mut checks = Checks::default();
+
+    checks.check("includes are given", || {
+        if config.includes.is_empty() {
+            return Err("No includes are configured".into());
+        }
+
+        Ok(())
+    });
+
+    for include in &config.includes {
+        checks.check(&format!("include {} exists", include.display()), || {
+            include.metadata()?;
+
+            Ok(())
+        });
+
+        checks.check(
+            &format!("include {} is below relative root", include.display()),
+            || {
+                if !include.starts_with(&config.relative_root) {
+                    return Err(format!(
+                        "Include is outside of relative root {}",
+                        config.relative_root.display()
+                    )
+                    .into());
+                }
+
+                Ok(())
+            },
+        );
+    }
+
+    for (name, batch_size) in [
+        ("batch_size", config.batch_size),
+        ("clean_batch_size", config.clean_batch_size),
+        ("restore_batch_size", config.restore_batch_size),
+    ] {
+        checks.check(&format!("{name} is positive"), || {
+            if batch_size == 0 {
+                return Err("Batch size must not be zero".into());
+            }
+
+            Ok(())
+        });
+    }
+
+    if config.bandwidth_limit.is_some() {
+        checks.check("bandwidth limit is unambiguous", || {
+            if config.adaptive_rate_limit.is_some() {
+                return Err("bandwidth_limit is ignored as adaptive_rate_limit is also set".into());
+            }
+
+            Ok(())
+        });
+    }
+
+    if config.command_timeout.is_some() {
+        checks.check("command_timeout is positive", || {
+            if config.command_timeout == Some(0) {
+                return Err("Timeout must not be zero".into());
+            }
+
+            Ok(())
+        });
+    }
+
+    for (name, threshold) in [
+        (
+            "excluded_warning_threshold",
+            config.excluded_warning_threshold,
+        ),
+        (
+            "shrinkage_warning_threshold",
+            config.shrinkage_warning_threshold,
+        ),
+    ] {
+        checks.check(&format!("{name} is a fraction"), || {
+            if !(0.0..=1.0).contains(&threshold) {
+                return Err(format!("{threshold} is not between 0 and 1").into());
+            }
+
+            Ok(())
+        });
+    }
+
+    for (name, duration) in [
+        ("retry_base_delay", Some(config.retry_base_delay.as_str())),
+        ("quota_max_wait", config.quota_max_wait.as_deref()),
+        (
+            "quota_recheck_interval",
+            config.quota_recheck_interval.as_deref(),
+        ),
+        (
+            "util_update_interval",
+            config.util_update_interval.as_deref(),
+        ),
+        ("exclude_older_than", config.exclude_older_than.as_deref()),
+        ("exclude_newer_than", config.exclude_newer_than.as_deref()),
+    ] {
+        if let Some(duration) = duration {
+            checks.check(&format!("{name} is a valid duration"), || {
+                parse_duration(duration)?;
+
+                Ok(())
+            });
+        }
+    }
+
+    if let Some(schedule) = &config.schedule {
+        checks.check("schedule is valid", || {
+            schedule.parse::<Schedule>()?;
+
+            Ok(())
+        });
+    }
+
+    if let Some(idevsutil_sha256) = &config.idevsutil_sha256 {
+        checks.check("idevsutil_sha256 is a SHA-256 hash", || {
+            from_hex(&idevsutil_sha256.to_ascii_lowercase())
+                .ok_or("Expected 64 hexadecimal digits")?;
+
+            Ok(())
+        });
+    }
+
+    for notify_email in &config.notify_email {
+        checks.check(&format!("notification address {notify_email}"), || {
+            if !notify_email
+                .rsplit_once('@')
+                .is_some_and(|(local, domain)| !local.is_empty() && !domain.trim().is_empty())
+            {
+                return Err("Address does not contain a domain".into());
+            }
+
+            Ok(())
+        });
+    }
+
+    if let Some(quota_warn_percent) = config.quota_warn_percent {
+        checks.check("quota_warn_percent is usable", || {
+            if !(0.0..=100.0).contains(&quota_warn_percent) {
+                return Err(format!("{quota_warn_percent} is not between 0 and 100").into());
+            }
+
+            if config.quota_limit.is_none() {
+                return Err("quota_warn_percent requires quota_limit to be set".into());
+            }
+
+            Ok(())
+        });
+    }
+
+    if config.smtp_host.is_some() {
+        checks.check("SMTP sender address is given", || {
+            if config.from_email.is_none() {
+                return Err("smtp_host requires from_email to be set".into());
+            }
+
+            Ok(())
+        });
+    }
+
+    if let Some(client_encryption_key) = &config.client_encryption_key {
+        checks.check("client encryption key exists", || {
+            client_encryption_key.metadata()?;
+
+            Ok(())
+        });
+    }
+
+    if config.snapshot.is_some() {
+        checks.check("snapshots are not combined with metadata manifest", || {
+            if config.metadata_manifest.is_some() {
+                return Err("Snapshots cannot be combined with a metadata manifest".into());
+            }
+
+            Ok(())
+        });
+    }
+
+    checks.check("idevsutil path is usable", || {
+        if !config.auto_download && !config.idevsutil_path.exists() {
+            return Err("idevsutil_dedup is missing and auto_download is disabled".into());
+        }
+
+        Ok(())
+    });
+
+    checks.finish()
+}
+
+#[derive(Default)]
+struct Checks {
+    passed: usize,
+    failed: usize,
+}
+
+impl Checks {
+    fn check<F>(&mut self, name: &str, f: F)
+    where
+        F: FnOnce() -> Fallible,
+    {
+        match f() {
+            Ok(()) => {
+                println!("ok      {name}");
+                self.passed += 1;
+            }
+            Err(err) => {
+                println!("FAILED  {name}: {err}");
+                self.failed += 1;
+            }
+        }
+    }
+
+    fn finish(self) -> Fallible {
+        println!("{} checks passed, {} failed", self.passed, self.failed);
+
+        if self.failed != 0 {
+            return Err(format!("{} configuration checks failed", self.failed).into());
+        }
+
+        Ok(())
+    }
+}
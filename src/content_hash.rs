@@ -0,0 +1,182 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{read, write};
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::str::from_utf8;
+use std::thread::scope;
+use std::time::{Duration, Instant};
+
+use super::{
+    format_size,
+    hash::{from_hex, hash_file_with, to_hex, Hash},
+    Fallible, SizeUnits,
+};
+
+pub const HASH_CACHE_FILE: &str = "hash_cache";
+
+// Each thread reuses a single buffer so that memory usage is bounded by the number of threads.
+const BUFFER_SIZE: usize = 1 << 20;
+
+pub struct ContentHashes {
+    threads: usize,
+    previous: HashMap<PathBuf, Hash>,
+    current: HashMap<PathBuf, Hash>,
+    hashed_bytes: u64,
+    hashing_time: Duration,
+}
+
+impl ContentHashes {
+    pub fn load(threads: usize) -> Fallible<Self> {
+        let previous = match read(HASH_CACHE_FILE) {
+            Ok(buf) => parse_cache(&buf),
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            threads: threads.max(1),
+            previous,
+            current: HashMap::new(),
+            hashed_bytes: 0,
+            hashing_time: Duration::ZERO,
+        })
+    }
+
+    pub fn retain_changed(&mut self, files: &mut Vec<PathBuf>) -> usize {
+        let start = Instant::now();
+
+        let chunk_size = files.len().div_ceil(self.threads).max(1);
+
+        let hashes = scope(|scope| {
+            let handles = files
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || hash_files(chunk)))
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        self.hashing_time += start.elapsed();
+
+        let mut unchanged = 0;
+        let mut hashes = hashes.into_iter();
+
+        files.retain(|file| {
+            let (hash, bytes) = match hashes.next().unwrap() {
+                Some(hash) => hash,
+                None => return true,
+            };
+
+            self.hashed_bytes += bytes;
+            self.current.insert(file.clone(), hash);
+
+            if self.previous.get(file) == Some(&hash) {
+                unchanged += 1;
+                false
+            } else {
+                true
+            }
+        });
+
+        unchanged
+    }
+
+    pub fn forget(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            self.previous.remove(path);
+            self.current.remove(path);
+        }
+    }
+
+    pub fn report(&self, units: SizeUnits) {
+        let secs = self.hashing_time.as_secs_f64();
+        let rate = if secs > 0.0 {
+            self.hashed_bytes as f64 / secs
+        } else {
+            0.0
+        };
+
+        let (size, unit) = format_size(self.hashed_bytes, units);
+        let (rate, rate_unit) = format_size(rate as u64, units);
+
+        info!(
+            "Hashed {size:.1} {unit} in {secs:.1} s ({rate:.1} {rate_unit}/s) using {} threads",
+            self.threads
+        );
+    }
+
+    pub fn save(mut self) -> Fallible {
+        for (path, hash) in self.current {
+            self.previous.insert(path, hash);
+        }
+
+        let mut buf = Vec::new();
+
+        for (path, hash) in &self.previous {
+            buf.extend_from_slice(format!("{} ", to_hex(hash)).as_bytes());
+            buf.extend_from_slice(path.as_os_str().as_bytes());
+            buf.push(b'\n');
+        }
+
+        write(HASH_CACHE_FILE, buf)?;
+
+        Ok(())
+    }
+}
+
+fn hash_files(files: &[PathBuf]) -> Vec<Option<(Hash, u64)>> {
+    let mut buf = vec![0; BUFFER_SIZE];
+
+    files
+        .iter()
+        .map(|file| match hash_file_with(file, &mut buf) {
+            Ok(hash) => Some(hash),
+            Err(err) => {
+                warn!("Failed to hash file {}: {}", file.display(), err);
+                None
+            }
+        })
+        .collect()
+}
+
+fn parse_cache(buf: &[u8]) -> HashMap<PathBuf, Hash> {
+    let mut entries = HashMap::new();
+
+    for line in buf.split(|byte| *byte == b'\n') {
+        let mut fields = line.splitn(2, |byte| *byte == b' ');
+
+        let hash = fields
+            .next()
+            .and_then(|hash| from_utf8(hash).ok())
+            .and_then(from_hex);
+
+        if let (Some(hash), Some(path)) = (hash, fields.next()) {
+            entries.insert(PathBuf::from(OsStr::from_bytes(path)), hash);
+        }
+    }
+
+    entries
+}
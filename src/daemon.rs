@@ -0,0 +1,220 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+use chrono::{offset::Local, DateTime, Datelike, TimeDelta, Timelike};
+
+use super::backup::{backup, Options};
+use super::snapshot::with_snapshots;
+use super::{connect, context, download_util, load_config, verify_util, Config, Fallible};
+
+pub fn daemon(config_path: &Path, no_download: bool, device: Option<&str>) -> Fallible {
+    install_reload_handler();
+
+    let (mut config, mut schedule) = load_schedule(config_path, no_download, device)?;
+    let mut next = schedule_next(&schedule)?;
+
+    loop {
+        if RELOAD.swap(false, Ordering::Relaxed) {
+            info!("Reloading config...");
+
+            match load_schedule(config_path, no_download, device) {
+                Ok((new_config, new_schedule)) => {
+                    config = new_config;
+                    schedule = new_schedule;
+                    next = schedule_next(&schedule)?;
+                }
+                Err(err) => warn!("Keeping previous config as reloading failed: {err}"),
+            }
+        }
+
+        if Local::now() >= next {
+            if let Err(err) = run_backup(&mut config) {
+                error!("Scheduled backup failed: {err}");
+            }
+
+            next = schedule_next(&schedule)?;
+        }
+
+        sleep(Duration::from_secs(1));
+    }
+}
+
+fn load_schedule(
+    config_path: &Path,
+    no_download: bool,
+    device: Option<&str>,
+) -> Fallible<(Config, Schedule)> {
+    let mut config = load_config(config_path)?;
+
+    if no_download {
+        config.auto_download = false;
+    }
+
+    if let Some(device) = device {
+        config.device_name = device.to_owned();
+    }
+
+    config.validate(true)?;
+
+    let schedule = config
+        .schedule
+        .as_deref()
+        .ok_or("No schedule configured")?
+        .parse()?;
+
+    Ok((config, schedule))
+}
+
+fn schedule_next(schedule: &Schedule) -> Fallible<DateTime<Local>> {
+    let next = schedule
+        .next_after(Local::now())
+        .ok_or("Schedule does not match any time within the next year")?;
+
+    info!("Next backup scheduled at {next}");
+
+    Ok(next)
+}
+
+fn run_backup(config: &mut Config) -> Fallible {
+    download_util(config, false).map_err(context("Failed to download idevsutil_dedup"))?;
+
+    verify_util(config).map_err(context("Failed to verify idevsutil_dedup"))?;
+
+    let (srv_ip, dev_id) = connect(config)?;
+
+    with_snapshots(config, |config| {
+        backup(config, &srv_ip, &dev_id, &Options::default())
+    })
+}
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_reload(_signum: libc::c_int) {
+    RELOAD.store(true, Ordering::Relaxed);
+}
+
+fn install_reload_handler() {
+    let handler = handle_reload as extern "C" fn(libc::c_int);
+
+    unsafe {
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+}
+
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = Box<dyn Error>;
+
+    fn from_str(val: &str) -> Fallible<Self> {
+        let fields = val.split_whitespace().collect::<Vec<_>>();
+
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("Schedule {val} does not have exactly five fields").into());
+        };
+
+        Ok(Schedule {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: parse_field(weekdays, 0, 6)?,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl Schedule {
+    fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut time = time.with_second(0)?.with_nanosecond(0)?;
+
+        for _ in 0..366 * 24 * 60 {
+            time += TimeDelta::minutes(1);
+
+            if self.matches(&time) {
+                return Some(time);
+            }
+        }
+
+        None
+    }
+
+    fn matches(&self, time: &DateTime<Local>) -> bool {
+        let day = self.days[time.day() as usize];
+        let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+
+        // Like cron, a restricted day of month or day of week is sufficient if both are restricted.
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        self.minutes[time.minute() as usize]
+            && self.hours[time.hour() as usize]
+            && self.months[time.month() as usize]
+            && day
+    }
+}
+
+fn parse_field(field: &str, min: usize, max: usize) -> Fallible<Vec<bool>> {
+    let mut values = vec![false; max + 1];
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>()?),
+            None => (part, 1),
+        };
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                None => {
+                    let value = range.parse()?;
+                    (value, value)
+                }
+            },
+        };
+
+        if start < min || end > max || start > end || step == 0 {
+            return Err(format!("Invalid schedule field {field}").into());
+        }
+
+        for value in (start..=end).step_by(step) {
+            values[value] = true;
+        }
+    }
+
+    Ok(values)
+}
@@ -0,0 +1,56 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::fs::rename;
+use std::path::Path;
+use std::process::Command;
+
+use super::{make_arg, Fallible};
+
+pub fn encrypt_file(key_file: &Path, src: &Path, dst: &Path) -> Fallible {
+    run_age("--encrypt", key_file, src, dst)
+}
+
+pub fn decrypt_in_place(key_file: &Path, path: &Path) -> Fallible {
+    let mut decrypted = path.as_os_str().to_os_string();
+    decrypted.push(".decrypted");
+
+    run_age("--decrypt", key_file, path, Path::new(&decrypted))?;
+    rename(decrypted, path)?;
+
+    Ok(())
+}
+
+fn run_age(mode: &str, key_file: &Path, src: &Path, dst: &Path) -> Fallible {
+    let status = Command::new("age")
+        .arg(mode)
+        .arg(make_arg("--identity=", key_file))
+        .arg(make_arg("--output=", dst))
+        .arg(src)
+        .status()?;
+
+    if !status.success() {
+        return Err(format!(
+            "age {mode} of {} failed with status {status}",
+            src.display()
+        )
+        .into());
+    }
+
+    Ok(())
+}
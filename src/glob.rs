@@ -0,0 +1,161 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+
+// Supports `*` and `?` within a component and `**` spanning any number of components.
+pub fn glob_match(pattern: &str, path: &Path) -> bool {
+    let pattern = pattern
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(str::as_bytes)
+        .collect::<Vec<_>>();
+
+    let path = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(component) => Some(component.as_bytes()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    match_components(&pattern, &path)
+}
+
+fn match_components(pattern: &[&[u8]], path: &[&[u8]]) -> bool {
+    match_wildcards(
+        pattern,
+        path,
+        |pattern| *pattern == b"**",
+        |pattern, component| match_component(pattern, component),
+    )
+}
+
+fn match_component(pattern: &[u8], name: &[u8]) -> bool {
+    match_wildcards(
+        pattern,
+        name,
+        |pattern| *pattern == b'*',
+        |pattern, byte| *pattern == b'?' || pattern == byte,
+    )
+}
+
+// Only the most recent wildcard needs to be revisited on a mismatch as any earlier one
+// could only consume less of the input which the later one can make up for. Hence this
+// takes time proportional to the product of the lengths instead of backtracking exponentially.
+fn match_wildcards<P, T>(
+    pattern: &[P],
+    input: &[T],
+    is_wildcard: impl Fn(&P) -> bool,
+    matches: impl Fn(&P, &T) -> bool,
+) -> bool {
+    let (mut pos, mut idx) = (0, 0);
+    let mut wildcard = None;
+
+    while idx < input.len() {
+        match pattern.get(pos) {
+            Some(pattern) if is_wildcard(pattern) => {
+                pos += 1;
+                wildcard = Some((pos, idx));
+            }
+            Some(pattern) if matches(pattern, &input[idx]) => {
+                pos += 1;
+                idx += 1;
+            }
+            _ => match &mut wildcard {
+                Some((wildcard_pos, wildcard_idx)) => {
+                    *wildcard_idx += 1;
+                    pos = *wildcard_pos;
+                    idx = *wildcard_idx;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[pos..].iter().all(is_wildcard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        glob_match(pattern, Path::new(path))
+    }
+
+    #[test]
+    fn star_matches_within_component() {
+        assert!(matches("/home/*.txt", "/home/notes.txt"));
+        assert!(matches("/home/*.txt", "/home/.txt"));
+        assert!(matches("/home/*", "/home/file"));
+        assert!(!matches("/home/*", "/home/dir/file"));
+        assert!(!matches("/home/*.txt", "/home/notes.md"));
+        assert!(matches("/home/a*b*c", "/home/axxbyyc"));
+        assert!(!matches("/home/a*b*c", "/home/axxbyy"));
+    }
+
+    #[test]
+    fn question_mark_matches_single_byte() {
+        assert!(matches("/home/file?", "/home/file1"));
+        assert!(!matches("/home/file?", "/home/file"));
+        assert!(!matches("/home/file?", "/home/file12"));
+    }
+
+    #[test]
+    fn double_star_at_start() {
+        assert!(matches("**/target", "/target"));
+        assert!(matches("**/target", "/home/src/target"));
+        assert!(!matches("**/target", "/home/src/target2"));
+    }
+
+    #[test]
+    fn double_star_in_middle() {
+        assert!(matches("/home/**/cache", "/home/cache"));
+        assert!(matches("/home/**/cache", "/home/user/app/cache"));
+        assert!(!matches("/home/**/cache", "/var/user/cache"));
+        assert!(!matches("/home/**/cache", "/home/user/cache/file"));
+    }
+
+    #[test]
+    fn double_star_at_end() {
+        assert!(matches("/home/**", "/home"));
+        assert!(matches("/home/**", "/home/user/file"));
+        assert!(!matches("/home/**", "/var/home"));
+    }
+
+    #[test]
+    fn empty_components_are_ignored() {
+        assert!(matches("", "/"));
+        assert!(matches("/", "/"));
+        assert!(!matches("", "/home"));
+        assert!(matches("//home//*/", "/home/user"));
+        assert!(matches("**", "/"));
+        assert!(!matches("*", "/"));
+    }
+
+    #[test]
+    fn repeated_wildcards_do_not_backtrack_exponentially() {
+        let name = format!("/{}", "a".repeat(64));
+        assert!(!matches("/*a*a*a*a*a*a*a*a*b", &name));
+
+        let path = "/a".repeat(64);
+        assert!(!matches("**/a/**/a/**/a/**/a/**/a/**/b", &path));
+    }
+}
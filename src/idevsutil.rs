@@ -0,0 +1,417 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+// The XML output of idevsutil_dedup is either a single tree element
+// or one item element per line.
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::str::from_utf8;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_roxmltree::from_str as from_xml_str;
+
+use super::Fallible;
+
+// Answer to `--getServerAddress`.
+#[derive(Deserialize)]
+#[serde(rename = "tree")]
+pub struct ServerIp {
+    #[serde(rename = "cmdUtilityServerIP")]
+    pub val: String,
+}
+
+// One device per item of `--list-device`.
+#[derive(Deserialize, Serialize)]
+#[serde(rename = "item")]
+pub struct Device {
+    pub device_id: String,
+    pub nick_name: String,
+}
+
+// Items of `--calc-quota`, only one of which carries the used quota, e.g. `1234 Bytes`.
+#[derive(Deserialize)]
+#[serde(rename = "item")]
+pub struct Quota {
+    pub quota_used: Option<String>,
+}
+
+// One directory entry per item of `--auth-list`, with type `D` or `F`.
+#[derive(Deserialize)]
+#[serde(rename = "item")]
+pub struct Resource {
+    #[serde(rename = "restype")]
+    pub type_: char,
+    #[serde(rename = "fname")]
+    pub name: PathBuf,
+    pub size: Option<String>,
+    pub mod_time: Option<String>,
+}
+
+// Progress of an upload or download, emitted repeatedly per file.
+//
+// Restores only rely on the cumulative total size, hence the other fields are optional.
+#[derive(Deserialize)]
+#[serde(rename = "item")]
+pub struct Transfer {
+    #[serde(rename = "per", default)]
+    pub percentage: String,
+    #[serde(rename = "fname", default)]
+    pub file_name: String,
+    #[serde(rename = "trf_type", default)]
+    pub type_: String,
+    #[serde(rename = "rate_trf", default)]
+    pub rate: String,
+    #[serde(rename = "tottrf_sz")]
+    pub total_size: u64,
+}
+
+// Result of `--delete-items`, only one of which carries the number of deleted items.
+#[derive(Deserialize)]
+#[serde(rename = "item")]
+pub struct Operation {
+    #[serde(rename = "tot_items_deleted")]
+    pub items_deleted: Option<usize>,
+}
+
+pub fn parse_tree<T: DeserializeOwned>(output: String) -> Fallible<T> {
+    let tree = find_tree(&output).ok_or("Did not find expected tree in output")?;
+
+    from_xml_str(tree).map_err(Into::into)
+}
+
+// Extracts the first complete tree element, ignoring any further trees or trailing content.
+fn find_tree(output: &str) -> Option<&str> {
+    let mut start = None;
+    let mut depth = 0_usize;
+    let mut pos = 0;
+
+    while let Some(offset) = output[pos..].find('<') {
+        let tag = pos + offset;
+        let end = tag + find_tag_end(&output[tag..])?;
+
+        let is_open = output[tag..].starts_with("<tree")
+            && output[tag + 5..]
+                .starts_with(|char: char| char.is_ascii_whitespace() || char == '>' || char == '/');
+        let is_close = output[tag..].starts_with("</tree");
+
+        if is_open && depth == 0 {
+            start = Some(tag);
+        }
+
+        if is_open && !output[..end].ends_with("/>") {
+            depth += 1;
+        } else if let Some(start) = start.filter(|_| is_open || is_close) {
+            depth = depth.checked_sub(usize::from(is_close))?;
+
+            if depth == 0 {
+                return Some(&output[start..end]);
+            }
+        }
+
+        pos = end;
+    }
+
+    None
+}
+
+fn find_tag_end(tag: &str) -> Option<usize> {
+    let mut quote = None;
+
+    for (pos, char) in tag.char_indices() {
+        match (quote, char) {
+            (None, '"' | '\'') => quote = Some(char),
+            (Some(open), _) if open == char => quote = None,
+            (None, '>') => return Some(pos + 1),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+// Unless strict, single malformed items, e.g. due to unescaped characters in file names,
+// are skipped instead of discarding all other items.
+pub fn parse_items<T: DeserializeOwned>(output: String, strict: bool) -> Fallible<Vec<T>> {
+    let mut items = Vec::new();
+    let mut skipped = 0;
+
+    for line in output.lines() {
+        if line.starts_with("<item") {
+            if let Some(item) = parse_item(line, strict, &mut skipped)? {
+                items.push(item);
+            }
+        }
+    }
+
+    report_skipped(skipped);
+
+    Ok(items)
+}
+
+pub fn stream_items<T, R, F>(reader: R, strict: bool, f: &mut F) -> Fallible
+where
+    T: DeserializeOwned,
+    R: BufRead,
+    F: FnMut(T) -> Fallible,
+{
+    let mut skipped = 0;
+
+    for line in reader.split(b'\n') {
+        let line = line?;
+
+        if line.starts_with(b"<item") {
+            let line = match from_utf8(&line) {
+                Ok(line) => line,
+                Err(err) if !strict => {
+                    warn!("Skipping item which is not valid UTF-8: {err}");
+                    skipped += 1;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            if let Some(item) = parse_item(line, strict, &mut skipped)? {
+                f(item)?;
+            }
+        }
+    }
+
+    report_skipped(skipped);
+
+    Ok(())
+}
+
+fn parse_item<T: DeserializeOwned>(
+    line: &str,
+    strict: bool,
+    skipped: &mut usize,
+) -> Fallible<Option<T>> {
+    match from_xml_str(line) {
+        Ok(item) => Ok(Some(item)),
+        Err(err) if !strict => {
+            warn!("Skipping malformed item {line}: {err}");
+            *skipped += 1;
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn report_skipped(skipped: usize) {
+    if skipped != 0 {
+        warn!("WARNING: Skipped {skipped} malformed items in output of idevsutil");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_tree_yields_self_closing_tree() {
+        assert_eq!(
+            find_tree(r#"<?xml version="1.0"?><tree a="1"/>"#),
+            Some(r#"<tree a="1"/>"#)
+        );
+    }
+
+    #[test]
+    fn find_tree_yields_only_first_tree() {
+        assert_eq!(
+            find_tree(r#"<tree a="1"><item/></tree><tree a="2"/>"#),
+            Some(r#"<tree a="1"><item/></tree>"#)
+        );
+        assert_eq!(
+            find_tree(r#"<tree a="1"/><tree a="2"/>"#),
+            Some(r#"<tree a="1"/>"#)
+        );
+    }
+
+    #[test]
+    fn find_tree_ignores_trailing_garbage() {
+        assert_eq!(
+            find_tree("<tree a=\"1\"></tree>\nconnection closed <<<"),
+            Some(r#"<tree a="1"></tree>"#)
+        );
+    }
+
+    #[test]
+    fn find_tree_handles_nested_trees_and_quoted_brackets() {
+        assert_eq!(
+            find_tree(r#"<tree a="x>y"><tree b="/>"></tree></tree>rest"#),
+            Some(r#"<tree a="x>y"><tree b="/>"></tree></tree>"#)
+        );
+    }
+
+    #[test]
+    fn find_tree_skips_similar_elements() {
+        assert_eq!(
+            find_tree(r#"<treeish/><tree a="1"/>"#),
+            Some(r#"<tree a="1"/>"#)
+        );
+    }
+
+    #[test]
+    fn find_tree_rejects_incomplete_output() {
+        assert_eq!(find_tree(""), None);
+        assert_eq!(find_tree("no xml at all"), None);
+        assert_eq!(find_tree(r#"<tree a="1"><item/>"#), None);
+        assert_eq!(find_tree(r#"<tree a="1"#), None);
+        assert_eq!(find_tree("</tree>"), None);
+    }
+
+    const SERVER_ADDRESS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tree message="SUCCESS" cmdUtilityServer="evs1.idrive.com" cmdUtilityServerIP="1.2.3.4" webApiServer="web1.idrive.com" webApiServerIP="5.6.7.8"/>
+"#;
+
+    const LIST_DEVICE: &str = r#"<item device_id="123456789" nick_name="laptop" os="Linux"/>
+<item device_id="987654321" nick_name="desktop" os="Linux"/>
+"#;
+
+    const CALC_QUOTA: &str = r#"<item files_count="10"/>
+<item quota_used="1234 Bytes"/>
+"#;
+
+    const AUTH_LIST: &str = r#"<item restype="D" fname="docs" mod_time="1700000000"/>
+<item restype="F" fname="notes.txt" size="42" mod_time="1700000001"/>
+<item restype="F" fname="broken & name" size="1"/>
+"#;
+
+    const TRANSFER: &str = r#"receiving file list
+<item per="50%" fname="home/file" trf_type="FULL" rate_trf="1.00MB/s" tottrf_sz="512"/>
+<item per="100%" fname="home/file" trf_type="FULL" rate_trf="1.00MB/s" tottrf_sz="1024"/>
+<item tottrf_sz="1024"/>
+"#;
+
+    const DELETE_ITEMS: &str = r#"<item operation="delete"/>
+<item tot_items_deleted="3"/>
+"#;
+
+    #[test]
+    fn parse_tree_yields_server_ip() {
+        let server_ip = parse_tree::<ServerIp>(SERVER_ADDRESS.to_owned()).unwrap();
+
+        assert_eq!(server_ip.val, "1.2.3.4");
+    }
+
+    #[test]
+    fn parse_tree_fails_without_tree() {
+        assert!(parse_tree::<ServerIp>(LIST_DEVICE.to_owned()).is_err());
+    }
+
+    #[test]
+    fn parse_items_yields_devices() {
+        let devices = parse_items::<Device>(LIST_DEVICE.to_owned(), true).unwrap();
+
+        let devices = devices
+            .iter()
+            .map(|device| (device.device_id.as_str(), device.nick_name.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(devices, [("123456789", "laptop"), ("987654321", "desktop")]);
+    }
+
+    #[test]
+    fn parse_items_yields_quota() {
+        let quota = parse_items::<Quota>(CALC_QUOTA.to_owned(), true).unwrap();
+
+        let quota_used = quota
+            .into_iter()
+            .find_map(|quota| quota.quota_used)
+            .unwrap();
+
+        assert_eq!(quota_used, "1234 Bytes");
+    }
+
+    #[test]
+    fn parse_items_skips_malformed_items_unless_strict() {
+        let resources = parse_items::<Resource>(AUTH_LIST.to_owned(), false).unwrap();
+
+        assert_eq!(resources.len(), 2);
+
+        assert_eq!(resources[0].type_, 'D');
+        assert_eq!(resources[0].name, PathBuf::from("docs"));
+        assert_eq!(resources[0].size, None);
+        assert_eq!(resources[0].mod_time.as_deref(), Some("1700000000"));
+
+        assert_eq!(resources[1].type_, 'F');
+        assert_eq!(resources[1].name, PathBuf::from("notes.txt"));
+        assert_eq!(resources[1].size.as_deref(), Some("42"));
+
+        assert!(parse_items::<Resource>(AUTH_LIST.to_owned(), true).is_err());
+    }
+
+    #[test]
+    fn parse_items_yields_deleted_items() {
+        let operations = parse_items::<Operation>(DELETE_ITEMS.to_owned(), true).unwrap();
+
+        let items_deleted = operations
+            .into_iter()
+            .find_map(|operation| operation.items_deleted);
+
+        assert_eq!(items_deleted, Some(3));
+    }
+
+    #[test]
+    fn stream_items_yields_transfers_as_they_arrive() {
+        let mut transfers = Vec::new();
+
+        stream_items(TRANSFER.as_bytes(), true, &mut |transfer: Transfer| {
+            transfers.push((transfer.percentage, transfer.file_name, transfer.total_size));
+
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            transfers,
+            [
+                ("50%".to_owned(), "home/file".to_owned(), 512),
+                ("100%".to_owned(), "home/file".to_owned(), 1024),
+                (String::new(), String::new(), 1024),
+            ]
+        );
+    }
+
+    #[test]
+    fn stream_items_skips_invalid_utf8_unless_strict() {
+        let output = b"<item tottrf_sz=\"1\" fname=\"\xff\"/>\n<item tottrf_sz=\"2\"/>\n";
+
+        let mut total_sizes = Vec::new();
+
+        stream_items(&output[..], false, &mut |transfer: Transfer| {
+            total_sizes.push(transfer.total_size);
+
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(total_sizes, [2]);
+
+        assert!(stream_items(&output[..], true, &mut |_: Transfer| Ok(())).is_err());
+    }
+
+    #[test]
+    fn stream_items_propagates_callback_errors() {
+        let res = stream_items(LIST_DEVICE.as_bytes(), true, &mut |_: Device| {
+            Err("stop".into())
+        });
+
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,55 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_interrupt(_signum: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// The handler is reset after the first interrupt so that pressing Ctrl-C again terminates immediately.
+pub fn install_interrupt_handler() {
+    let handler = handle_interrupt as extern "C" fn(libc::c_int);
+
+    unsafe {
+        let mut action = std::mem::zeroed::<libc::sigaction>();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+
+    INSTALLED.store(true, Ordering::Relaxed);
+}
+
+// If set, idevsutil_dedup is started in its own process group
+// so that the terminal does not interrupt an in-flight batch.
+pub fn interrupt_handler_installed() -> bool {
+    INSTALLED.load(Ordering::Relaxed)
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+// Follows the shell convention of 128 plus the signal number.
+pub const INTERRUPTED_EXIT_CODE: i32 = 128 + libc::SIGINT;
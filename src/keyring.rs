@@ -0,0 +1,143 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::process::{Command, Stdio};
+
+use super::{Config, Fallible};
+
+pub fn resolve_secrets(config: &mut Config) -> Fallible {
+    if let Some(command) = &config.password_command {
+        if !config.password.is_empty() {
+            return Err("Both password and password_command are configured".into());
+        }
+
+        config.password = run_secret_command("password", command)?;
+    }
+
+    if let Some(command) = &config.encryption_key_command {
+        if !config.encryption_key.is_empty() {
+            return Err("Both encryption_key and encryption_key_command are configured".into());
+        }
+
+        config.encryption_key = run_secret_command("encryption key", command)?;
+    }
+
+    if let (Some(service), Some(user)) = (&config.keyring_service, &config.keyring_user) {
+        if config.password_command.is_none() {
+            if let Some(password) = lookup_secret(service, user, "password")? {
+                config.password = password;
+            }
+        }
+
+        if config.encryption_key_command.is_none() {
+            if let Some(encryption_key) = lookup_secret(service, user, "encryption_key")? {
+                config.encryption_key = encryption_key;
+            }
+        }
+    }
+
+    if config.password.is_empty() {
+        return Err("No password configured inline, via command or in keyring".into());
+    }
+
+    if config.encryption_key.is_empty() {
+        return Err("No encryption key configured inline, via command or in keyring".into());
+    }
+
+    Ok(())
+}
+
+pub fn set_secret(config: &Config, name: &str) -> Fallible {
+    let (service, user) = keyring_entry(config)
+        .ok_or("Both keyring_service and keyring_user need to be configured")?;
+
+    let status = Command::new("secret-tool")
+        .arg("store")
+        .arg(format!("--label=idrive_backup {name} of {user}"))
+        .arg("service")
+        .arg(service)
+        .arg("user")
+        .arg(user)
+        .arg("secret")
+        .arg(name)
+        .status()?;
+
+    if !status.success() {
+        return Err(format!("Failed to store {name} using secret-tool").into());
+    }
+
+    Ok(())
+}
+
+// Like password managers invoked by restic or borg, the command is run by the shell
+// and its standard output, without the trailing newline, is taken as the secret.
+fn run_secret_command(name: &str, command: &str) -> Fallible<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| format!("Failed to run command for {name}: {err}"))?;
+
+    if !output.status.success() {
+        return Err(format!("Command for {name} failed with status {}", output.status).into());
+    }
+
+    let mut secret = String::from_utf8(output.stdout)?;
+
+    if secret.ends_with('\n') {
+        secret.pop();
+    }
+
+    if secret.is_empty() {
+        return Err(format!("Command for {name} did not yield a secret").into());
+    }
+
+    Ok(secret)
+}
+
+fn keyring_entry(config: &Config) -> Option<(&str, &str)> {
+    Some((
+        config.keyring_service.as_deref()?,
+        config.keyring_user.as_deref()?,
+    ))
+}
+
+fn lookup_secret(service: &str, user: &str, name: &str) -> Fallible<Option<String>> {
+    let output = Command::new("secret-tool")
+        .arg("lookup")
+        .arg("service")
+        .arg(service)
+        .arg("user")
+        .arg(user)
+        .arg("secret")
+        .arg(name)
+        .output()?;
+
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let mut secret = String::from_utf8(output.stdout)?;
+
+    if secret.ends_with('\n') {
+        secret.pop();
+    }
+
+    Ok(Some(secret))
+}
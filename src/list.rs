@@ -0,0 +1,217 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use serde::Serialize;
+use serde_json::to_writer_pretty as to_json_writer;
+
+use super::{context, walk_dir, Config, Fallible};
+
+#[derive(Clone, Copy)]
+pub enum ListFormat {
+    Csv,
+    Json,
+    Plain,
+}
+
+#[derive(Serialize)]
+struct Listing<'a> {
+    device_name: &'a str,
+    device_id: &'a str,
+    timestamp: String,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    path: PathBuf,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: Option<u64>,
+}
+
+pub fn list(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    sub_dir: &Path,
+    export: Option<&Path>,
+    format: ListFormat,
+    recursive: bool,
+) -> Fallible {
+    info!(
+        "Listing backup of {} ({}) from {}...",
+        config.device_name, dev_id, srv_ip
+    );
+
+    let mut entries = Vec::new();
+
+    walk_dir(config, srv_ip, dev_id, sub_dir, |path, size, _| {
+        entries.push(Entry {
+            path: path.clone(),
+            kind: if size.is_some() { "file" } else { "dir" },
+            size,
+        });
+
+        Ok(Some(path).filter(|_| recursive))
+    })
+    .map_err(context("Failed to walk remote directory"))?;
+
+    if config.print_args {
+        return Ok(());
+    }
+
+    let listing = Listing {
+        device_name: &config.device_name,
+        device_id: dev_id,
+        timestamp: Local::now().to_rfc3339(),
+        entries,
+    };
+
+    let mut writer: Box<dyn Write> = match export {
+        Some(export) => Box::new(BufWriter::new(File::create(export)?)),
+        None => Box::new(BufWriter::new(stdout().lock())),
+    };
+
+    match format {
+        ListFormat::Csv => write_csv(&mut writer, &listing)?,
+        ListFormat::Json => {
+            to_json_writer(&mut writer, &listing)?;
+            writeln!(writer)?;
+        }
+        ListFormat::Plain => write_plain(&mut writer, &listing)?,
+    }
+
+    writer.flush()?;
+
+    if let Some(export) = export {
+        info!(
+            "Exported {} entries to {}",
+            listing.entries.len(),
+            export.display()
+        );
+    }
+
+    Ok(())
+}
+
+// The header is written as comment lines so that the records themselves remain plain CSV.
+fn write_csv(writer: &mut dyn Write, listing: &Listing) -> Fallible {
+    writeln!(
+        writer,
+        "# device: {} ({})",
+        listing.device_name, listing.device_id
+    )?;
+    writeln!(writer, "# timestamp: {}", listing.timestamp)?;
+    writeln!(writer, "path,type,size")?;
+
+    for entry in &listing.entries {
+        writeln!(
+            writer,
+            "{},{},{}",
+            csv_field(&entry.path.to_string_lossy()),
+            entry.kind,
+            entry.size.map_or(String::new(), |size| size.to_string())
+        )?;
+    }
+
+    Ok(())
+}
+
+// One path per line with directories marked by a trailing slash, e.g. for use with grep.
+fn write_plain(writer: &mut dyn Write, listing: &Listing) -> Fallible {
+    for entry in &listing.entries {
+        let slash = if entry.size.is_none() { "/" } else { "" };
+
+        writeln!(writer, "{}{}", entry.path.display(), slash)?;
+    }
+
+    Ok(())
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::read_to_string;
+
+    use tempfile::NamedTempFile;
+
+    use super::super::test_util::{config, FakeUtil};
+
+    fn list_entries(recursive: bool) -> (usize, String) {
+        let export = NamedTempFile::new().unwrap();
+
+        let mut config = config("");
+        let fake = FakeUtil::install(&mut config, |invocation| {
+            let output = if invocation.server_path() == "user@1.2.3.4::home/" {
+                "<item restype=\"D\" fname=\"docs\"/>\n<item restype=\"F\" fname=\"a.txt\" size=\"1\"/>"
+            } else {
+                r#"<item restype="F" fname="b.txt" size="2"/>"#
+            };
+
+            (output.to_owned(), 0)
+        });
+
+        list(
+            &config,
+            "1.2.3.4",
+            "5c0b123",
+            Path::new("/"),
+            Some(export.path()),
+            ListFormat::Plain,
+            recursive,
+        )
+        .unwrap();
+
+        (
+            fake.invocations().len(),
+            read_to_string(export.path()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn list_stays_in_given_directory() {
+        let (invocations, listing) = list_entries(false);
+
+        assert_eq!(invocations, 1);
+        assert!(listing.contains("/docs"));
+        assert!(listing.contains("/a.txt"));
+        assert!(!listing.contains("b.txt"));
+    }
+
+    #[test]
+    fn list_descends_if_recursive() {
+        let (invocations, listing) = list_entries(true);
+
+        assert_eq!(invocations, 2);
+        assert!(listing.contains("/docs/b.txt"));
+    }
+}
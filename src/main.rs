@@ -16,330 +16,2584 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
 */
+#[macro_use]
+mod log;
+
 mod backup;
+mod benchmark;
 mod clean;
+mod config_test;
+mod content_hash;
+mod daemon;
+mod encryption;
+mod glob;
+mod hash;
+mod idevsutil;
+mod interrupt;
+mod keyring;
+mod list;
+mod maintenance;
+mod metadata;
+mod migrate_config;
+mod mtime_cache;
+mod rate_limit;
 mod restore;
 mod restore_missing;
-
+mod scan;
+mod snapshot;
+mod staging;
+#[cfg(test)]
+mod test_util;
+mod timing;
+mod verify;
+mod watch;
+mod xattrs;
+mod zip;
+
+use std::collections::BTreeMap;
+use std::env::set_current_dir;
 use std::error::Error;
 use std::ffi::{OsStr, OsString};
-use std::fs::{remove_file, set_permissions, write, File, Permissions};
-use std::io::BufReader;
+use std::fmt;
+use std::fs::{read, read_dir, read_to_string, set_permissions, write, File, Permissions};
+use std::io::{self, stdin, BufReader, ErrorKind, IsTerminal, Read};
+use std::mem::swap;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-
-use clap::{command, Arg, ArgAction, Command as Subcommand};
-use serde::{de::DeserializeOwned, Deserialize};
-use serde_roxmltree::from_str as from_xml_str;
-use serde_yaml::from_reader as from_yaml_reader;
-use tempfile::{NamedTempFile, TempDir};
-
-use self::backup::backup;
-use self::clean::clean;
-use self::restore::restore;
+use std::process::{exit, Child, Command, ExitStatus, Output, Stdio};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{available_parallelism, scope, sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{offset::Local, DateTime};
+use clap::{command, value_parser, Arg, ArgAction, ArgMatches, Command as Subcommand};
+use schemars::{schema_for, JsonSchema};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::to_string_pretty as to_json_string;
+use serde_yaml::{
+    from_reader as from_yaml_reader, from_value as from_yaml_value, to_string as to_yaml_string,
+    to_value as to_yaml_value, Value as YamlValue,
+};
+use tempfile::{Builder, NamedTempFile, TempDir};
+
+use self::backup::{
+    backup, Options as BackupOptions, OutputFormat, PartialFailure, PARTIAL_FAILURE_EXIT_CODE,
+};
+use self::benchmark::benchmark;
+use self::clean::{clean, Options as CleanOptions};
+use self::config_test::config_test;
+use self::daemon::daemon;
+use self::glob::glob_match;
+use self::hash::{hash_file, to_hex};
+use self::idevsutil::{parse_items, parse_tree, stream_items, Device, Quota, Resource, ServerIp};
+use self::interrupt::{
+    install_interrupt_handler, interrupt_handler_installed, interrupted, INTERRUPTED_EXIT_CODE,
+};
+use self::keyring::{resolve_secrets, set_secret};
+use self::list::{list, ListFormat};
+use self::log::{set_max_level, Level};
+use self::maintenance::{maintenance, Retention};
+use self::migrate_config::migrate_config;
+use self::rate_limit::AdaptiveRateLimit;
+use self::restore::{restore, restore_files, restore_glob, Options as RestoreOptions};
 use self::restore_missing::restore_missing;
+use self::scan::scan;
+use self::snapshot::{with_snapshots, SnapshotHooks};
+use self::timing::{timed, util_label, Timings};
+use self::verify::verify;
+use self::watch::watch;
+use self::zip::extract_entry;
 
 fn main() -> Fallible {
+    // The Rust runtime ignores SIGPIPE so that printing to a closed pipe would panic,
+    // but we rather terminate quietly like other command line tools do.
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+
     let matches = command!()
-        .subcommand(Subcommand::new("backup"))
+        .arg(
+            Arg::new("work_dir")
+                .long("work-dir")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_parser(value_parser!(PathBuf))
+                .default_value(CONFIG_FILE),
+        )
+        .arg(Arg::new("device").long("device"))
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::Count),
+        )
+        .arg(Arg::new("json").long("json").action(ArgAction::SetTrue))
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("verbose"),
+        )
+        .arg(
+            Arg::new("dump_config")
+                .long("dump-config")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no_download")
+                .long("no-download")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("update_util")
+                .long("update-util")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("print_args")
+                .long("print-args")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("check_notify_email")
+                .long("check-notify-email")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("versions")
+                .long("versions")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("config_test")
+                .long("config-test")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("assume_yes")
+                .short('y')
+                .long("assume-yes")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("trace_timing")
+                .long("trace-timing")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strict_parsing")
+                .long("strict-parsing")
+                .action(ArgAction::SetTrue),
+        )
+        .subcommand(
+            Subcommand::new("backup")
+                .arg(
+                    Arg::new("progress_interval")
+                        .long("progress-interval")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("max_depth")
+                        .long("max-depth")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("output_format")
+                        .long("output-format")
+                        .value_parser(["default", "rsync"])
+                        .default_value("default"),
+                )
+                .arg(
+                    Arg::new("since_last_run")
+                        .long("since-last-run")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(Arg::new("since").long("since").value_parser(parse_since))
+                .arg(
+                    Arg::new("max_runtime")
+                        .long("max-runtime")
+                        .value_parser(parse_duration),
+                )
+                .arg(
+                    Arg::new("stats_only")
+                        .long("stats-only")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("compare_quota")
+                        .long("compare-quota-before-after")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no_notify")
+                        .long("no-notify")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no_summary")
+                        .long("no-summary")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("parallel_hash")
+                        .long("parallel-hash")
+                        .value_parser(value_parser!(usize))
+                        .num_args(0..=1)
+                        .default_missing_value("0"),
+                )
+                .arg(
+                    Arg::new("exclude_caches")
+                        .long("exclude-caches")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("only")
+                        .long("only")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("stats_json")
+                        .long("stats-json")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("only_new")
+                        .long("only-new")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(Arg::new("resume").long("resume").action(ArgAction::SetTrue)),
+        )
+        .subcommand(restore_command())
         .subcommand(
-            Subcommand::new("restore")
-                .arg(Arg::new("sub_dir").long("sub-dir").default_value("/"))
-                .arg(Arg::new("out_dir").long("out-dir"))
+            Subcommand::new("clean")
                 .arg(
-                    Arg::new("missing")
-                        .long("missing")
+                    Arg::new("dry_run")
+                        .long("dry-run")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("plan")
+                        .long("plan")
+                        .value_parser(value_parser!(PathBuf))
+                        .conflicts_with_all(["dry_run", "apply"]),
+                )
+                .arg(
+                    Arg::new("apply")
+                        .long("apply")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("min_age")
+                        .long("min-age")
+                        .value_name("DAYS")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            Subcommand::new("scan").arg(
+                Arg::new("path")
+                    .value_parser(value_parser!(PathBuf))
+                    .required(true),
+            ),
+        )
+        .subcommand(list_command())
+        .subcommand(
+            Subcommand::new("verify").arg(
+                Arg::new("sub_dir")
+                    .long("sub-dir")
+                    .value_parser(value_parser!(PathBuf))
+                    .default_value("/"),
+            ),
+        )
+        .subcommand(
+            Subcommand::new("benchmark")
+                .arg(
+                    Arg::new("files")
+                        .long("files")
+                        .value_parser(value_parser!(usize))
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .value_parser(value_parser!(u64))
+                        .default_value("10485760"),
                 ),
         )
         .subcommand(
-            Subcommand::new("clean").arg(
-                Arg::new("dry_run")
-                    .long("dry-run")
-                    .action(ArgAction::SetTrue),
+            Subcommand::new("watch").arg(
+                Arg::new("debounce")
+                    .long("debounce")
+                    .value_parser(parse_duration)
+                    .default_value("10s"),
+            ),
+        )
+        .subcommand(Subcommand::new("daemon"))
+        .subcommand(Subcommand::new("maintenance"))
+        .subcommand(Subcommand::new("migrate-config"))
+        .subcommand(Subcommand::new("config-schema"))
+        .subcommand(
+            Subcommand::new("devices").arg(
+                Arg::new("format")
+                    .long("format")
+                    .value_parser(["human", "json"])
+                    .default_value("human"),
+            ),
+        )
+        .subcommand(Subcommand::new("quota"))
+        .subcommand(
+            Subcommand::new("set-secret").arg(
+                Arg::new("name")
+                    .value_parser(["password", "encryption_key"])
+                    .required(true),
             ),
         )
         .get_matches();
 
-    download_util().map_err(context("Failed to download idevsutil_dedup"))?;
+    // Machine-readable results are printed to stdout, so progress is only logged if asked for.
+    if matches.get_flag("quiet") || (matches.get_flag("json") && matches.get_count("verbose") == 0)
+    {
+        set_max_level(Level::Warn);
+    } else if matches.get_count("verbose") != 0 {
+        set_max_level(Level::Debug);
+    }
 
-    let config = read_config().map_err(context("Failed to read config"))?;
-    let srv_ip = get_server_ip(&config).map_err(context("Failed to determine server IP"))?;
-    let dev_id =
-        get_device_id(&config, &srv_ip).map_err(context("Failed to determine device ID"))?;
+    // Config, state and idevsutil_dedup are all resolved relative to the working directory.
+    if let Some(work_dir) = matches.get_one::<PathBuf>("work_dir") {
+        set_current_dir(work_dir).map_err(|err| {
+            format!(
+                "Failed to change into working directory {}: {}",
+                work_dir.display(),
+                err
+            )
+        })?;
+    }
+
+    let config_path = matches.get_one::<PathBuf>("config").unwrap();
+
+    if matches.get_flag("versions") {
+        return print_versions(config_path).map_err(context("Failed to determine versions"));
+    }
+
+    if let Some(("migrate-config", _)) = matches.subcommand() {
+        return migrate_config(config_path).map_err(context("Failed to migrate config"));
+    }
+
+    if let Some(("config-schema", _)) = matches.subcommand() {
+        return config_schema().map_err(context("Failed to export config schema"));
+    }
+
+    let mut config = read_config(config_path).map_err(context("Failed to read config"))?;
+
+    if matches.get_flag("config_test") {
+        return config_test(&config);
+    }
+
+    if let Some(("maintenance", _)) = matches.subcommand() {
+        return maintenance(&config).map_err(context("Failed to trim logs and reports"));
+    }
+
+    if let Some(("set-secret", matches)) = matches.subcommand() {
+        let name = matches.get_one::<String>("name").unwrap();
+
+        return set_secret(&config, name).map_err(context("Failed to store secret"));
+    }
+
+    resolve_secrets(&mut config).map_err(context("Failed to resolve secrets"))?;
+
+    let no_download = matches.get_flag("no_download");
+
+    if no_download {
+        config.auto_download = false;
+    }
+
+    let device = matches.get_one::<String>("device");
+
+    if let Some(device) = device {
+        config.device_name = device.clone();
+    }
+
+    config.print_args = matches.get_flag("print_args");
+    config.json = matches.get_flag("json");
+    config.assume_yes = matches.get_flag("assume_yes");
+    config.strict_parsing |= matches.get_flag("strict_parsing");
+
+    if matches.get_flag("trace_timing") {
+        config.timings = Some(Timings::default());
+    }
+
+    if matches.get_flag("dump_config") {
+        return dump_config(&config).map_err(context("Failed to dump config"));
+    }
 
-    match matches.subcommand() {
-        None | Some(("backup", _)) => backup(&config, &srv_ip, &dev_id),
-        Some(("restore", matches)) => {
+    if matches.get_flag("check_notify_email") {
+        if let Err(err) = check_notify_email(&config) {
+            warn!("Could not check notification address: {err}");
+        }
+    }
+
+    // Restores onto a fresh machine must not fail just because the includes do not exist yet.
+    let reads_includes = matches!(
+        matches.subcommand(),
+        None | Some(("backup" | "scan" | "watch" | "daemon", _))
+    );
+
+    config
+        .validate(reads_includes)
+        .map_err(context("Invalid config"))?;
+
+    if let Some(("scan", matches)) = matches.subcommand() {
+        let path = matches.get_one::<PathBuf>("path").unwrap();
+
+        return scan(&config, path);
+    }
+
+    if let Some(("daemon", _)) = matches.subcommand() {
+        return daemon(config_path, no_download, device.map(String::as_str));
+    }
+
+    if !config.print_args {
+        download_util(&config, matches.get_flag("update_util"))
+            .map_err(context("Failed to download idevsutil_dedup"))?;
+
+        verify_util(&config).map_err(context("Failed to verify idevsutil_dedup"))?;
+    }
+
+    if let Some(("devices", matches)) = matches.subcommand() {
+        let json = matches.get_one::<String>("format").unwrap() == "json";
+
+        return print_devices(&config, json).map_err(context("Failed to list devices"));
+    }
+
+    if let Some(("quota", _)) = matches.subcommand() {
+        return print_quota(&config).map_err(context("Failed to determine quota"));
+    }
+
+    let (srv_ip, dev_id) = connect(&config)?;
+
+    let res = match matches.subcommand() {
+        None => with_snapshots(&mut config, |config| {
+            install_interrupt_handler();
+
+            let opts = BackupOptions {
+                stats_json: config.json,
+                ..Default::default()
+            };
+
+            backup(config, &srv_ip, &dev_id, &opts)
+        }),
+        Some(("backup", matches)) => {
+            let opts = BackupOptions {
+                progress_interval: matches
+                    .get_one::<u64>("progress_interval")
+                    .map(|secs| Duration::from_secs(*secs)),
+                max_depth: matches.get_one::<usize>("max_depth").copied(),
+                output_format: match matches.get_one::<String>("output_format").unwrap().as_str() {
+                    "rsync" => OutputFormat::Rsync,
+                    _ => OutputFormat::Default,
+                },
+                since_last_run: matches.get_flag("since_last_run"),
+                since: matches.get_one::<i64>("since").copied(),
+                max_runtime: matches.get_one::<Duration>("max_runtime").copied(),
+                stats_only: matches.get_flag("stats_only"),
+                compare_quota: matches.get_flag("compare_quota"),
+                no_notify: matches.get_flag("no_notify"),
+                no_summary: matches.get_flag("no_summary"),
+                parallel_hash: matches.get_one::<usize>("parallel_hash").map(|threads| {
+                    if *threads != 0 {
+                        *threads
+                    } else {
+                        available_parallelism().map_or(1, Into::into)
+                    }
+                }),
+                exclude_caches: matches.get_flag("exclude_caches"),
+                only: matches.get_one::<PathBuf>("only").cloned(),
+                stats_json: matches.get_flag("stats_json") || config.json,
+                only_new: matches.get_flag("only_new"),
+                dry_run: matches.get_flag("dry_run"),
+                resume: matches.get_flag("resume"),
+            };
+
+            install_interrupt_handler();
+
+            with_snapshots(&mut config, |config| {
+                backup(config, &srv_ip, &dev_id, &opts)
+            })
+        }
+        Some(("restore", matches)) => run_restore(&config, &srv_ip, &dev_id, matches),
+        Some(("list", matches)) => {
+            let sub_dir = matches.get_one::<PathBuf>("sub_dir").unwrap();
+            let export = matches.get_one::<PathBuf>("export");
+            let format = match matches.get_one::<String>("format").unwrap().as_str() {
+                "json" => ListFormat::Json,
+                "plain" => ListFormat::Plain,
+                _ => ListFormat::Csv,
+            };
+
+            list(
+                &config,
+                &srv_ip,
+                &dev_id,
+                sub_dir,
+                export.map(PathBuf::as_path),
+                format,
+                matches.get_flag("recursive"),
+            )
+        }
+        Some(("verify", matches)) => {
             let sub_dir = matches.get_one::<PathBuf>("sub_dir").unwrap();
-            let out_dir = matches.get_one::<PathBuf>("out_dir").unwrap();
-            let missing = matches.get_flag("missing");
 
-            if missing {
-                restore_missing(&config, &srv_ip, &dev_id, sub_dir, out_dir)
-            } else {
-                restore(&config, &srv_ip, &dev_id, sub_dir, out_dir)
-            }
+            verify(&config, &srv_ip, &dev_id, sub_dir)
+        }
+        Some(("watch", matches)) => {
+            let debounce = *matches.get_one::<Duration>("debounce").unwrap();
+
+            watch(&config, &srv_ip, &dev_id, debounce)
+        }
+        Some(("benchmark", matches)) => {
+            let files = *matches.get_one::<usize>("files").unwrap();
+            let size = *matches.get_one::<u64>("size").unwrap();
+
+            benchmark(&config, &srv_ip, &dev_id, files, size)
         }
         Some(("clean", matches)) => {
-            let dry_run = matches.get_flag("dry_run");
+            let opts = CleanOptions {
+                dry_run: matches.get_flag("dry_run"),
+                plan: matches.get_one::<PathBuf>("plan").cloned(),
+                apply: matches.get_one::<PathBuf>("apply").cloned(),
+                min_age: matches.get_one::<u64>("min_age").copied(),
+            };
 
-            clean(&config, &srv_ip, &dev_id, dry_run)
+            install_interrupt_handler();
+
+            clean(&config, &srv_ip, &dev_id, &opts)
         }
         _ => unreachable!(),
+    };
+
+    if let Some(timings) = &config.timings {
+        timings.report();
+    }
+
+    // Failures of single files were already reported, so only the exit code remains to be set.
+    let partial_failure = res.as_ref().is_err_and(|err| err.is::<PartialFailure>());
+
+    if interrupted() {
+        if !partial_failure {
+            res?;
+        }
+
+        exit(INTERRUPTED_EXIT_CODE);
+    }
+
+    if partial_failure {
+        exit(PARTIAL_FAILURE_EXIT_CODE);
     }
+
+    res
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct Config {
     username: String,
+    #[serde(default)]
     password: String,
+    #[serde(default)]
     encryption_key: String,
+    #[serde(default)]
+    previous_encryption_keys: Vec<String>,
+    #[serde(skip)]
+    config_path: PathBuf,
+    password_command: Option<String>,
+    encryption_key_command: Option<String>,
+    keyring_service: Option<String>,
+    keyring_user: Option<String>,
     device_name: String,
-    notify_email: String,
+    #[serde(default = "default_device_id_prefix")]
+    device_id_prefix: String,
+    #[serde(default = "default_device_id_suffix")]
+    device_id_suffix: String,
+    #[serde(deserialize_with = "one_or_many")]
+    #[schemars(with = "OneOrMany<String>")]
+    notify_email: Vec<String>,
+    smtp_host: Option<String>,
+    smtp_port: Option<u16>,
+    smtp_user: Option<String>,
+    smtp_password: Option<String>,
+    from_email: Option<String>,
+    webhook_url: Option<String>,
+    #[serde(default)]
+    webhook_format: WebhookFormat,
+    #[serde(rename = "includes", deserialize_with = "one_or_many")]
+    #[schemars(with = "OneOrMany<Include>")]
+    include_roots: Vec<Include>,
+    // Split from the include roots when reading the config.
+    #[serde(skip)]
     includes: Vec<PathBuf>,
+    #[serde(skip)]
+    root_excludes: Vec<(PathBuf, Vec<PathBuf>)>,
+    #[serde(deserialize_with = "one_or_many")]
+    #[schemars(with = "OneOrMany<PathBuf>")]
     excludes: Vec<PathBuf>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[schemars(with = "OneOrMany<PathBuf>")]
+    exclude_contents: Vec<PathBuf>,
     #[serde(default = "default_batch_size")]
     batch_size: usize,
+    #[serde(default = "default_item_batch_size")]
+    clean_batch_size: usize,
+    #[serde(default = "default_item_batch_size")]
+    restore_batch_size: usize,
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    #[serde(default = "default_concurrency")]
+    list_concurrency: usize,
+    #[serde(default)]
+    healthcheck_url: HealthcheckUrl,
+    #[serde(default)]
+    one_file_system: bool,
+    #[serde(default)]
+    submounts: BTreeMap<PathBuf, SubmountPolicy>,
+    #[serde(default = "default_excluded_warning_threshold")]
+    excluded_warning_threshold: f64,
+    metadata_manifest: Option<PathBuf>,
+    history_file: Option<PathBuf>,
+    #[serde(default)]
+    history_format: HistoryFormat,
+    quota_limit: Option<u64>,
+    quota_warn_percent: Option<f64>,
+    min_free_quota: Option<u64>,
+    quota_max_wait: Option<String>,
+    quota_recheck_interval: Option<String>,
+    #[serde(default = "default_quota_check_timeout")]
+    quota_check_timeout: u64,
+    command_timeout: Option<u64>,
+    #[serde(default = "default_max_retries")]
+    max_retries: usize,
+    #[serde(default = "default_retry_base_delay")]
+    retry_base_delay: String,
+    #[serde(default)]
+    skip_empty_files: bool,
+    #[serde(default)]
+    deterministic_order: bool,
+    #[serde(default = "default_relative_root")]
+    relative_root: PathBuf,
+    #[serde(default)]
+    verify_transfers: bool,
+    #[serde(default)]
+    use_mtime_cache: bool,
+    // In KiB/s, with zero meaning unlimited
+    bandwidth_limit: Option<u64>,
+    adaptive_rate_limit: Option<AdaptiveRateLimit>,
+    snapshot: Option<SnapshotHooks>,
+    #[serde(default)]
+    include_symlinks: bool,
+    #[serde(default = "default_follow_symlinks")]
+    follow_symlinks: bool,
+    client_encryption_key: Option<PathBuf>,
+    schedule: Option<String>,
+    util_update_interval: Option<String>,
+    idevsutil_sha256: Option<String>,
+    restore_max_overwrite: Option<usize>,
+    #[serde(default)]
+    preserve_xattrs: bool,
+    #[serde(default)]
+    summary_format: SummaryFormat,
+    #[serde(default)]
+    size_units: SizeUnits,
+    path_prefix: Option<String>,
+    #[serde(skip)]
+    print_args: bool,
+    #[serde(skip)]
+    json: bool,
+    #[serde(skip)]
+    assume_yes: bool,
+    #[serde(skip)]
+    timings: Option<Timings>,
+    #[serde(skip)]
+    util_runner: Option<Arc<dyn UtilRunner>>,
+    exclude_older_than: Option<String>,
+    exclude_newer_than: Option<String>,
+    #[serde(default)]
+    include_extensions: Vec<String>,
+    #[serde(default)]
+    exclude_caches: bool,
+    #[serde(default)]
+    skip_hidden: bool,
+    #[serde(default)]
+    case_insensitive_excludes: bool,
+    skip_dirs_over: Option<usize>,
+    max_file_size: Option<u64>,
+    log_retention: Option<Retention>,
+    report_retention: Option<Retention>,
+    #[serde(default)]
+    strict_parsing: bool,
+    #[serde(default)]
+    require_readable: bool,
+    #[serde(default = "default_shrinkage_warning_threshold")]
+    shrinkage_warning_threshold: f64,
+    #[serde(default)]
+    fail_on_shrinkage: bool,
+    #[serde(default = "default_auto_download")]
+    auto_download: bool,
+    #[serde(default = "default_idevsutil_path")]
+    idevsutil_path: PathBuf,
 }
 
-fn default_batch_size() -> usize {
-    1000
+#[derive(Default, Deserialize, Serialize, JsonSchema)]
+pub struct HealthcheckUrl {
+    start: Option<String>,
+    success: Option<String>,
+    failure: Option<String>,
 }
 
-fn read_config() -> Fallible<Config> {
-    let config_file = File::open("config.yaml")?;
-    let config = from_yaml_reader(BufReader::new(config_file))?;
-
-    Ok(config)
+#[derive(Clone, Copy, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmountPolicy {
+    Follow,
+    Skip,
+    SeparateRoot,
 }
 
-fn download_util() -> Fallible {
-    if Path::new("idevsutil_dedup").exists() {
-        return Ok(());
-    }
-
-    eprintln!("Downloading idevsutil_dedup...");
-
-    let status = Command::new("curl")
-        .arg("-o")
-        .arg("IDrive_linux_64bit.zip")
-        .arg("https://www.idrivedownloads.com/downloads/linux/download-options/IDrive_linux_64bit.zip")
-        .status()?;
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SummaryFormat {
+    #[default]
+    Detailed,
+    Compact,
+}
 
-    if !status.success() {
-        return Err("Failed to download idevsutil_dedup using curl".into());
-    }
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryFormat {
+    #[default]
+    Json,
+    Csv,
+}
 
-    let status = Command::new("unzip")
-        .arg("-j")
-        .arg("IDrive_linux_64bit.zip")
-        .arg("IDrive_linux_64bit/idevsutil_dedup")
-        .status()?;
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    Slack,
+    Discord,
+    #[default]
+    Generic,
+}
 
-    if !status.success() {
-        return Err("Failed to extract idevsutil_dedup using unzip".into());
-    }
+#[derive(Clone, Copy, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SizeUnits {
+    #[default]
+    Binary,
+    Si,
+}
 
-    remove_file("IDrive_linux_64bit.zip")?;
-    set_permissions("idevsutil_dedup", Permissions::from_mode(0o755))?;
+// Either just a path or a path together with excludes which only apply below it.
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+enum Include {
+    Path(PathBuf),
+    Root {
+        path: PathBuf,
+        #[serde(default, deserialize_with = "one_or_many")]
+        #[schemars(with = "OneOrMany<PathBuf>")]
+        excludes: Vec<PathBuf>,
+    },
+}
 
-    Ok(())
+// Accepts a single value where a list of values is expected.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
 }
 
-fn run_util<I, S>(config: &Config, args: I) -> Fallible<String>
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
 where
-    I: IntoIterator<Item = S>,
-    S: AsRef<OsStr>,
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
 {
-    let pass_file = NamedTempFile::new()?;
-    write(&pass_file, &config.password)?;
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}
 
-    let key_file = NamedTempFile::new()?;
-    write(&key_file, &config.encryption_key)?;
+fn default_batch_size() -> usize {
+    1000
+}
 
-    let temp_dir = TempDir::new()?;
+// Deleting or restoring items may apply to whole directories, hence smaller batches.
+fn default_item_batch_size() -> usize {
+    100
+}
 
-    let output = Command::new("./idevsutil_dedup")
-        .arg(make_arg("--password-file=", pass_file.path()))
-        .arg(make_arg("--pvt-key=", key_file.path()))
-        .arg(make_arg("--temp=", temp_dir.path()))
-        .args(args)
-        .env("LANG", "C")
-        .output()?;
+fn default_concurrency() -> usize {
+    1
+}
 
-    if !output.status.success() {
-        return Err(format!("idevsutil_dedup failed with status {:?}", output.status).into());
-    }
+fn default_excluded_warning_threshold() -> f64 {
+    0.5
+}
 
-    Ok(String::from_utf8(output.stdout)?)
+fn default_quota_check_timeout() -> u64 {
+    30
 }
 
-fn parse_tree<T: DeserializeOwned>(output: String) -> Fallible<T> {
-    let tree = if let Some(pos) = output.find("<tree") {
-        &output[pos..]
-    } else {
-        return Err("Did not find expected tree in output".into());
-    };
+fn default_max_retries() -> usize {
+    3
+}
 
-    from_xml_str(tree).map_err(Into::into)
+fn default_retry_base_delay() -> String {
+    "5s".to_owned()
 }
 
-fn parse_items<T: DeserializeOwned>(output: String) -> Fallible<Vec<T>> {
-    let mut items = Vec::new();
+fn default_relative_root() -> PathBuf {
+    PathBuf::from("/")
+}
 
-    for line in output.lines() {
-        if line.starts_with("<item") {
-            items.push(from_xml_str(line)?);
-        }
-    }
+fn default_shrinkage_warning_threshold() -> f64 {
+    0.5
+}
 
-    Ok(items)
+fn default_auto_download() -> bool {
+    true
 }
 
-fn get_server_ip(config: &Config) -> Fallible<String> {
-    let output = run_util(config, ["--getServerAddress", &config.username])?;
+fn default_follow_symlinks() -> bool {
+    true
+}
 
-    #[derive(Deserialize)]
-    #[serde(rename = "tree")]
-    struct ServerIp {
-        #[serde(rename = "cmdUtilityServerIP")]
-        val: String,
-    }
+// The IDs listed by idevsutil need to be wrapped like this to address a device.
+const DEFAULT_DEVICE_ID_PREFIX: &str = "5c0b";
+const DEFAULT_DEVICE_ID_SUFFIX: &str = "4b5z";
 
-    let srv_ip = parse_tree::<ServerIp>(output)?;
+fn default_device_id_prefix() -> String {
+    DEFAULT_DEVICE_ID_PREFIX.to_owned()
+}
 
-    Ok(srv_ip.val)
+fn default_device_id_suffix() -> String {
+    DEFAULT_DEVICE_ID_SUFFIX.to_owned()
 }
 
-fn get_device_id(config: &Config, srv_ip: &str) -> Fallible<String> {
-    let output = run_util(
-        config,
-        [
-            "--list-device",
-            &format!("{}@{}::home/", config.username, srv_ip),
-        ],
-    )?;
+fn default_idevsutil_path() -> PathBuf {
+    PathBuf::from("./idevsutil_dedup")
+}
 
-    #[derive(Deserialize)]
-    #[serde(rename = "item")]
-    struct Device {
-        device_id: String,
-        nick_name: String,
-    }
+const CONFIG_FILE: &str = "config.yaml";
 
-    let devices = parse_items::<Device>(output)?;
+fn load_config(path: &Path) -> Fallible<Config> {
+    let mut config = read_config(path).map_err(context("Failed to read config"))?;
 
-    for device in devices {
-        if device.nick_name == config.device_name {
-            return Ok(format!("5c0b{}4b5z", device.device_id));
-        }
-    }
+    resolve_secrets(&mut config).map_err(context("Failed to resolve secrets"))?;
 
-    Err("Failed to resolve device ID".into())
+    Ok(config)
 }
 
-fn get_quota(config: &Config, srv_ip: &str) -> Fallible<u64> {
-    let output = run_util(
-        config,
-        [
-            "--calc-quota",
-            &format!("{}@{}::home/", config.username, srv_ip),
-        ],
-    )?;
+fn connect(config: &Config) -> Fallible<(String, String)> {
+    // Without running idevsutil_dedup, there is no output to parse and placeholders are used instead.
+    if config.print_args {
+        let srv_ip = "SERVER_IP".to_owned();
+        let _ = get_server_ip(config);
+        let _ = get_device_id(config, &srv_ip);
 
-    #[derive(Deserialize)]
-    #[serde(rename = "item")]
-    struct Quota {
-        quota_used: Option<String>,
+        return Ok((srv_ip, "DEVICE_ID".to_owned()));
     }
 
-    let items = parse_items::<Quota>(output)?;
+    let srv_ip = get_server_ip(config).map_err(context("Failed to determine server IP"))?;
+    let dev_id =
+        get_device_id(config, &srv_ip).map_err(context("Failed to determine device ID"))?;
 
-    for item in items {
-        if let Some(quota_used) = item.quota_used {
-            if let Some(quota_used) = quota_used.strip_suffix(" Bytes") {
-                let quota_used = quota_used.parse()?;
+    Ok((srv_ip, dev_id))
+}
 
-                return Ok(quota_used);
+fn read_config(path: &Path) -> Fallible<Config> {
+    let value = read_config_value(path)?;
+    let mut config: Config = from_yaml_value(value.clone())
+        .map_err(|err| format!("Invalid config file {}: {err}", path.display()))?;
+
+    config.config_path = path.to_owned();
+
+    for key in unknown_keys(&value, &config)? {
+        warn!("Ignoring unknown config key {key}, please check for typos");
+    }
+
+    // Relative excludes of an include root are resolved against it unless they are globs,
+    // which are matched anywhere below the root.
+    for include in &config.include_roots {
+        match include {
+            Include::Path(path) => config.includes.push(path.clone()),
+            Include::Root { path, excludes } => {
+                config.includes.push(path.clone());
+
+                if !excludes.is_empty() {
+                    let excludes = excludes
+                        .iter()
+                        .map(|exclude| {
+                            if is_glob(exclude) {
+                                exclude.clone()
+                            } else {
+                                path.join(exclude)
+                            }
+                        })
+                        .collect();
+
+                    let root = path.canonicalize().unwrap_or_else(|_| path.clone());
+
+                    config.root_excludes.push((root, excludes));
+                }
             }
         }
     }
 
-    Err("Failed to parse quota items".into())
-}
-
-fn list_dir(
-    config: &Config,
-    srv_ip: &str,
-    dev_id: &str,
-    dir: &Path,
-) -> Fallible<impl Iterator<Item = (PathBuf, bool)>> {
-    let output = run_util(
-        config,
-        [
-            OsStr::new("--auth-list"),
-            OsStr::new("--xml-output"),
-            &make_arg("--device-id=", dev_id),
-            &make_arg(&format!("{}@{}::home", config.username, srv_ip), dir),
-        ],
-    )?;
+    // Candidate paths are canonicalized before matching, hence so must be the excludes.
+    for exclude in config
+        .excludes
+        .iter_mut()
+        .chain(&mut config.exclude_contents)
+        .chain(
+            config
+                .root_excludes
+                .iter_mut()
+                .flat_map(|(_, excludes)| excludes),
+        )
+    {
+        if is_glob(exclude) {
+            continue;
+        }
 
-    #[derive(Deserialize)]
-    #[serde(rename = "item")]
-    struct Resource {
-        #[serde(rename = "restype")]
-        type_: char,
-        #[serde(rename = "fname")]
-        name: PathBuf,
+        if let Ok(canonical_exclude) = exclude.canonicalize() {
+            *exclude = canonical_exclude;
+        }
     }
 
-    let resources = parse_items::<Resource>(output)?;
+    if let Some(prefix) = &mut config.path_prefix {
+        if prefix.contains("{hostname}") {
+            *prefix = prefix.replace("{hostname}", &get_hostname()?);
+        }
+    }
 
-    Ok(resources
-        .into_iter()
-        .filter_map(|resource| match resource.type_ {
-            'D' => Some((resource.name, true)),
-            'F' => Some((resource.name, false)),
-            type_ => {
-                eprintln!("Skipping unknown resource type: {type_}");
+    Ok(config)
+}
 
-                None
+impl Config {
+    // Collects all problems instead of stopping at the first one. Missing excludes are only warned about
+    // as they might legitimately be absent on some machines.
+    fn validate(&self, includes: bool) -> Fallible {
+        let mut problems = Vec::new();
+
+        if includes {
+            for include in &self.includes {
+                let res = if include.is_dir() {
+                    read_dir(include).map(drop)
+                } else {
+                    File::open(include).map(drop)
+                };
+
+                if let Err(err) = res {
+                    problems.push(format!(
+                        "Include {} is not readable: {err}",
+                        include.display()
+                    ));
+                }
             }
-        }))
-}
 
-fn walk_dir<F: FnMut(PathBuf) -> Fallible<Option<PathBuf>>>(
-    config: &Config,
-    srv_ip: &str,
-    dev_id: &str,
-    dir: &Path,
-    mut f: F,
-) -> Fallible {
-    let mut dirs = vec![dir.to_path_buf()];
+            for exclude in self
+                .excludes
+                .iter()
+                .chain(self.root_excludes.iter().flat_map(|(_, excludes)| excludes))
+            {
+                if !is_glob(exclude) && !exclude.exists() {
+                    warn!(
+                        "Exclude {} does not exist and will not match anything",
+                        exclude.display()
+                    );
+                }
+            }
+        }
 
-    while let Some(dir) = dirs.pop() {
-        for (entry, is_dir) in list_dir(config, srv_ip, dev_id, &dir)? {
-            let path = dir.join(entry);
+        for notify_email in &self.notify_email {
+            if !notify_email
+                .rsplit_once('@')
+                .is_some_and(|(local, domain)| !local.is_empty() && !domain.trim().is_empty())
+            {
+                problems.push(format!(
+                    "Notification address {notify_email} is not an email address"
+                ));
+            }
+        }
 
-            if let Some(path) = f(path)? {
-                if is_dir {
-                    dirs.push(path);
-                }
+        for (name, batch_size) in [
+            ("batch_size", self.batch_size),
+            ("clean_batch_size", self.clean_batch_size),
+            ("restore_batch_size", self.restore_batch_size),
+        ] {
+            if batch_size == 0 {
+                problems.push(format!("{name} must not be zero"));
             }
         }
+
+        if !problems.is_empty() {
+            return Err(problems.join("; ").into());
+        }
+
+        Ok(())
+    }
+}
+
+fn dump_config(config: &Config) -> Fallible {
+    let mut value = to_yaml_value(config)?;
+
+    for key in [
+        "password",
+        "encryption_key",
+        "previous_encryption_keys",
+        "smtp_password",
+        "webhook_url",
+        "healthcheck_url",
+    ] {
+        if let Some(secret) = value.get_mut(key) {
+            *secret = YamlValue::from("<redacted>");
+        }
     }
 
+    print!("{}", to_yaml_string(&value)?);
+
     Ok(())
 }
 
-fn make_arg<S: AsRef<OsStr>>(pre: &str, val: S) -> OsString {
-    let mut arg = OsString::new();
-    arg.push(pre);
-    arg.push(val);
-    arg
-}
+// Falls back to the default location of idevsutil_dedup as the versions
+// are also useful when reporting problems with reading the config.
+fn print_versions(config_path: &Path) -> Fallible {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+
+    let idevsutil_path = read_config(config_path)
+        .map_or_else(|_| default_idevsutil_path(), |config| config.idevsutil_path);
+
+    if !idevsutil_path.exists() {
+        println!(
+            "idevsutil_dedup not yet downloaded to {}",
+            idevsutil_path.display()
+        );
+
+        return Ok(());
+    }
+
+    let output = Command::new(&idevsutil_path)
+        .arg("--version")
+        .env("LANG", "C")
+        .output()?;
+
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    let version = output
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or("of unknown version");
+
+    println!("idevsutil_dedup {version}");
+    println!("idevsutil_dedup SHA-256 {}", sha256(&idevsutil_path)?);
+
+    Ok(())
+}
+
+fn config_schema() -> Fallible {
+    let schema = schema_for!(Config);
+
+    println!("{}", to_json_string(&schema)?);
+
+    Ok(())
+}
+
+// Relies on dig being installed to avoid linking a DNS resolver.
+fn check_notify_email(config: &Config) -> Fallible {
+    for notify_email in &config.notify_email {
+        let domain = notify_email
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.trim())
+            .filter(|domain| !domain.is_empty())
+            .ok_or_else(|| format!("Address {notify_email} does not contain a domain"))?;
+
+        let output = Command::new("dig")
+            .arg("+short")
+            .arg("MX")
+            .arg(domain)
+            .output()?;
+
+        if !output.status.success() {
+            return Err("Failed to look up MX records using dig".into());
+        }
+
+        if String::from_utf8(output.stdout)?.trim().is_empty() {
+            warn!(
+                "WARNING: Domain {domain} of notification address {notify_email} has no MX records, summaries will likely not be delivered!"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn read_config_value(path: &Path) -> Fallible<YamlValue> {
+    let config_file = File::open(path)
+        .map_err(|err| format!("Failed to open config file {}: {err}", path.display()))?;
+    let value = from_yaml_reader(BufReader::new(config_file))
+        .map_err(|err| format!("Failed to parse config file {}: {err}", path.display()))?;
+
+    Ok(value)
+}
+
+fn unknown_keys(value: &YamlValue, config: &Config) -> Fallible<Vec<String>> {
+    fn collect(prefix: &str, value: &YamlValue, known: &YamlValue, keys: &mut Vec<String>) {
+        let (value, known) = match (value.as_mapping(), known.as_mapping()) {
+            (Some(value), Some(known)) => (value, known),
+            _ => return,
+        };
+
+        for (key, value) in value {
+            let name = match key.as_str() {
+                Some(name) => format!("{prefix}{name}"),
+                None => format!("{prefix}{key:?}"),
+            };
+
+            match known.get(key) {
+                Some(known) => collect(&format!("{name}."), value, known, keys),
+                None => keys.push(name),
+            }
+        }
+    }
+
+    let known = to_yaml_value(config)?;
+
+    let mut keys = Vec::new();
+    collect("", value, &known, &mut keys);
+
+    Ok(keys)
+}
+
+fn download_util(config: &Config, update: bool) -> Fallible {
+    let exists = config.idevsutil_path.exists();
+
+    if exists && !update && !util_update_due(config)? {
+        return Ok(());
+    }
+
+    if !config.auto_download {
+        if exists {
+            warn!("Skipping update of idevsutil_dedup as automatic download is disabled");
+
+            return Ok(());
+        }
+
+        return Err(format!(
+            "{} does not exist and automatic download is disabled",
+            config.idevsutil_path.display()
+        )
+        .into());
+    }
+
+    if exists {
+        info!("Checking for update of idevsutil_dedup...");
+    } else {
+        info!("Downloading idevsutil_dedup...");
+    }
+
+    let dir = match config.idevsutil_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    // Partial downloads are removed when the temporary file is dropped.
+    let archive = NamedTempFile::new_in(dir)?;
+
+    let contents = download_archive(archive.as_file(), archive.path())?;
+
+    let util = NamedTempFile::new_in(dir)?;
+    write(&util, contents)?;
+
+    if let Some(pinned) = &config.idevsutil_sha256 {
+        let hash = sha256(util.path())?;
+
+        if !hash.eq_ignore_ascii_case(pinned) {
+            return Err(format!(
+                "Downloaded idevsutil_dedup has SHA-256 {hash} which does not match pinned {pinned}"
+            )
+            .into());
+        }
+    }
+
+    if exists && read(&config.idevsutil_path)? == read(util.path())? {
+        info!("idevsutil_dedup is up to date");
+    } else {
+        set_permissions(util.path(), Permissions::from_mode(0o755))?;
+        util.persist(&config.idevsutil_path)?;
+
+        if exists {
+            info!("Updated idevsutil_dedup to latest version");
+        }
+    }
+
+    write(UTIL_CHECKED_FILE, Local::now().timestamp().to_string())?;
+
+    Ok(())
+}
+
+const DOWNLOAD_ATTEMPTS: usize = 3;
+
+const UTIL_ARCHIVE_ENTRY: &str = "IDrive_linux_64bit/idevsutil_dedup";
+
+// Interrupted transfers are resumed using HTTP range requests while archives
+// failing the integrity check are downloaded again from scratch.
+//
+// Yields the extracted contents of idevsutil_dedup.
+fn download_archive(file: &File, path: &Path) -> Fallible<Vec<u8>> {
+    for attempt in 1..=DOWNLOAD_ATTEMPTS {
+        let status = Command::new("curl")
+            .arg("--fail")
+            .arg("--retry")
+            .arg("3")
+            .arg("--retry-connrefused")
+            .arg("--continue-at")
+            .arg("-")
+            .arg("-o")
+            .arg(path)
+            .arg("https://www.idrivedownloads.com/downloads/linux/download-options/IDrive_linux_64bit.zip")
+            .status()?;
+
+        if !status.success() {
+            warn!("Download attempt {attempt} of {DOWNLOAD_ATTEMPTS} using curl failed");
+
+            // Resuming might fail itself, e.g. if the server does not support range requests.
+            if status.code() == Some(33) {
+                file.set_len(0)?;
+            }
+
+            continue;
+        }
+
+        match extract_entry(&read(path)?, UTIL_ARCHIVE_ENTRY) {
+            Ok(contents) if !contents.is_empty() => return Ok(contents),
+            Ok(_) => warn!(
+                "Downloaded archive contains empty idevsutil_dedup in attempt {attempt} of {DOWNLOAD_ATTEMPTS}"
+            ),
+            Err(err) => warn!(
+                "Downloaded archive failed integrity check in attempt {attempt} of {DOWNLOAD_ATTEMPTS}: {err}"
+            ),
+        }
+
+        file.set_len(0)?;
+    }
+
+    Err("Failed to download idevsutil_dedup using curl".into())
+}
+
+fn verify_util(config: &Config) -> Fallible {
+    let pinned = match &config.idevsutil_sha256 {
+        Some(pinned) => pinned,
+        None => return Ok(()),
+    };
+
+    let hash = sha256(&config.idevsutil_path)?;
+
+    if !hash.eq_ignore_ascii_case(pinned) {
+        return Err(format!(
+            "Refusing to run {} as its SHA-256 {} does not match pinned {}",
+            config.idevsutil_path.display(),
+            hash,
+            pinned
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn sha256(path: &Path) -> Fallible<String> {
+    Ok(to_hex(&hash_file(path)?))
+}
+
+const UTIL_CHECKED_FILE: &str = "util_checked";
+
+fn util_update_due(config: &Config) -> Fallible<bool> {
+    let interval = match &config.util_update_interval {
+        Some(interval) => parse_duration(interval)?,
+        None => return Ok(false),
+    };
+
+    let checked = match read_to_string(UTIL_CHECKED_FILE) {
+        Ok(checked) => checked.trim().parse::<i64>()?,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(Local::now().timestamp() - checked >= interval.as_secs() as i64)
+}
+
+fn run_util<I, S>(config: &Config, args: I) -> Fallible<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    run_util_with_timeout(config, args, None)
+}
+
+fn run_util_with_timeout<I, S>(
+    config: &Config,
+    args: I,
+    timeout: Option<Duration>,
+) -> Fallible<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    if config.print_args {
+        print_args(args);
+        return Ok(String::new());
+    }
+
+    let args = collect_args(args);
+
+    // An explicit timeout bounds all attempts together so that e.g. pre-flight checks
+    // cannot delay the actual operation by retrying, whereas the configured one applies to each.
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    retry_transient_until(config, &util_label(&args), deadline, || {
+        let timeout = deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .or_else(|| command_timeout(config));
+
+        // Failing to spawn the process or to wait for it is not retried.
+        let mut process = util_runner(config).spawn(config, 0, &args)?;
+
+        let output = timed(config, &util_label(&args), || {
+            wait_with_timeout(&mut process.child, timeout)
+        })?;
+
+        if !output.status.success() {
+            return Err(util_failed(output.status, &output.stdout, &output.stderr));
+        }
+
+        Ok(String::from_utf8(output.stdout)?)
+    })
+}
+
+// Repeats failures which are likely transient like dropped connections or timeouts using exponential
+// backoff, but returns others like missing directories or rejected credentials immediately.
+fn retry_transient<T, F>(config: &Config, what: &str, f: F) -> Fallible<T>
+where
+    F: FnMut() -> Fallible<T>,
+{
+    retry_transient_until(config, what, None, f)
+}
+
+// No further attempt is made if it could not start before the deadline.
+fn retry_transient_until<T, F>(
+    config: &Config,
+    what: &str,
+    deadline: Option<Instant>,
+    mut f: F,
+) -> Fallible<T>
+where
+    F: FnMut() -> Fallible<T>,
+{
+    let mut attempt = 0;
+
+    loop {
+        let err = match f() {
+            Ok(val) => return Ok(val),
+            Err(err) if attempt < config.max_retries && UtilFailed::transient(&*err) => err,
+            Err(err) => return Err(err),
+        };
+
+        attempt += 1;
+
+        let delay = retry_delay(config, attempt)?;
+
+        if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+            return Err(err);
+        }
+
+        warn!(
+            "Retrying {} in {} s (attempt {} of {}) as it failed: {}",
+            what,
+            delay.as_secs(),
+            attempt,
+            config.max_retries,
+            err
+        );
+
+        sleep(delay);
+    }
+}
+
+fn retry_delay(config: &Config, attempt: usize) -> Fallible<Duration> {
+    Ok(parse_duration(&config.retry_base_delay)?.saturating_mul(1 << (attempt - 1).min(16)))
+}
+
+// Parses items as their lines arrive instead of buffering the whole output,
+// so that memory usage does not grow with the number of transferred files.
+fn run_util_streaming<T, I, S, F>(config: &Config, args: I, f: F) -> Fallible
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+    F: FnMut(T) -> Fallible,
+{
+    run_util_streaming_with_key(config, 0, args, f)
+}
+
+fn run_util_streaming_with_key<T, I, S, F>(
+    config: &Config,
+    key_index: usize,
+    args: I,
+    mut f: F,
+) -> Fallible
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+    F: FnMut(T) -> Fallible,
+{
+    if config.print_args {
+        print_args(args);
+        return Ok(());
+    }
+
+    let args = collect_args(args);
+
+    let mut process = util_runner(config).spawn(config, key_index, &args)?;
+    let child = &mut process.child;
+
+    let timeout = command_timeout(config);
+
+    let (res, status, stderr, timed_out) = timed(config, &util_label(&args), || -> Fallible<_> {
+        let watchdog = timeout.map(|timeout| kill_after(child, timeout));
+
+        let stderr = read_to_end(child.stderr.take().unwrap());
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+
+        let res = stream_items(stdout, config.strict_parsing, &mut f);
+
+        if res.is_err() {
+            let _ = child.kill();
+        }
+
+        let status = child.wait()?;
+        let stderr = stderr.join().unwrap()?;
+
+        let timed_out = watchdog.is_some_and(|(done, watchdog)| {
+            drop(done);
+            watchdog.join().unwrap()
+        });
+
+        Ok((res, status, stderr, timed_out))
+    })?;
+
+    if timed_out {
+        return Err(util_timed_out(status, timeout.unwrap()));
+    }
+
+    res?;
+
+    if !status.success() {
+        return Err(util_failed(status, &[], &stderr));
+    }
+
+    Ok(())
+}
+
+fn run_transfer_streaming<T, I, S, F>(config: &Config, args: I, f: F) -> Fallible
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+    F: FnMut(T) -> Fallible,
+{
+    run_transfer_streaming_with_key(config, 0, args, f)
+}
+
+// Checksumming forces idevsutil_dedup to read and hash every file on both ends,
+// which can slow down transfers considerably.
+fn run_transfer_streaming_with_key<T, I, S, F>(
+    config: &Config,
+    key_index: usize,
+    args: I,
+    f: F,
+) -> Fallible
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+    F: FnMut(T) -> Fallible,
+{
+    let checksum = config
+        .verify_transfers
+        .then_some(OsString::from("--checksum"));
+
+    let bwlimit = config
+        .adaptive_rate_limit
+        .as_ref()
+        .map(AdaptiveRateLimit::arg)
+        .or_else(|| bandwidth_limit(config));
+
+    let res = run_util_streaming_with_key(
+        config,
+        key_index,
+        checksum
+            .into_iter()
+            .chain(bwlimit)
+            .chain(args.into_iter().map(|arg| arg.as_ref().to_os_string())),
+        f,
+    );
+
+    if let Some(adaptive_rate_limit) = &config.adaptive_rate_limit {
+        adaptive_rate_limit.record(res.is_ok());
+    }
+
+    res
+}
+
+// Only transfers are throttled, listings and quota calculations are not.
+fn bandwidth_limit(config: &Config) -> Option<OsString> {
+    config
+        .bandwidth_limit
+        .filter(|bandwidth_limit| *bandwidth_limit != 0)
+        .map(|bandwidth_limit| format!("--bwlimit={bandwidth_limit}").into())
+}
+
+// Archived data might have been encrypted using a previous key, hence restores fall back to those
+// if the current key fails. Backups always use the current key. The key is passed to each invocation
+// instead of being stored in the config as concurrent restore jobs might use different keys.
+fn run_restore_streaming<T, I, S, F>(config: &Config, args: I, mut f: F) -> Fallible
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+    F: FnMut(T) -> Fallible,
+{
+    let args = collect_args(args);
+
+    let mut res = run_transfer_streaming_with_key(config, 0, &args, &mut f);
+
+    for index in 1..=config.previous_encryption_keys.len() {
+        let err = match res {
+            Ok(()) => break,
+            Err(err) => err,
+        };
+
+        warn!("Retrying restore using previous encryption key {index}: {err}");
+
+        res = run_transfer_streaming_with_key(config, index, &args, &mut f);
+    }
+
+    res
+}
+
+// Index zero denotes the current key, the others index the previous keys starting from one.
+fn encryption_key(config: &Config, key_index: usize) -> &str {
+    match key_index {
+        0 => &config.encryption_key,
+        index => &config.previous_encryption_keys[index - 1],
+    }
+}
+
+fn collect_args<I, S>(args: I) -> Vec<OsString>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    args.into_iter()
+        .map(|arg| arg.as_ref().to_os_string())
+        .collect()
+}
+
+// The password and encryption key are passed via temporary files which are not shown here.
+fn print_args<I, S>(args: I)
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut line = String::from(
+        "idevsutil_dedup --password-file=<redacted> --pvt-key=<redacted> --temp=<temp>",
+    );
+
+    for arg in args {
+        line.push(' ');
+        line.push_str(&arg.as_ref().to_string_lossy());
+    }
+
+    println!("{line}");
+}
+
+// The temporary files are referenced by the command line of the child process,
+// hence they must not be removed before it has exited, even on errors or panics.
+struct UtilProcess {
+    child: Child,
+    _pass_file: NamedTempFile,
+    _key_file: NamedTempFile,
+    _temp_dir: TempDir,
+}
+
+impl Drop for UtilProcess {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+// Every invocation of idevsutil_dedup is spawned by a runner so that tests can substitute canned output.
+trait UtilRunner: Send + Sync {
+    fn spawn(&self, config: &Config, key_index: usize, args: &[OsString]) -> Fallible<UtilProcess>;
+}
+
+struct IdevsutilRunner;
+
+impl UtilRunner for IdevsutilRunner {
+    fn spawn(&self, config: &Config, key_index: usize, args: &[OsString]) -> Fallible<UtilProcess> {
+        spawn_util(config, key_index, args)
+    }
+}
+
+fn util_runner(config: &Config) -> &dyn UtilRunner {
+    config.util_runner.as_deref().unwrap_or(&IdevsutilRunner)
+}
+
+fn spawn_util(config: &Config, key_index: usize, args: &[OsString]) -> Fallible<UtilProcess> {
+    let pass_file = new_temp_file()?;
+    write(&pass_file, &config.password)?;
+
+    let key_file = new_temp_file()?;
+    write(&key_file, encryption_key(config, key_index))?;
+
+    let temp_dir = new_temp_dir()?;
+
+    let mut cmd = Command::new(&config.idevsutil_path);
+
+    if interrupt_handler_installed() {
+        cmd.process_group(0);
+    }
+
+    let child = cmd
+        .arg(make_arg("--password-file=", pass_file.path()))
+        .arg(make_arg("--pvt-key=", key_file.path()))
+        .arg(make_arg("--temp=", temp_dir.path()))
+        .args(args)
+        .env("LANG", "C")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    Ok(UtilProcess {
+        child,
+        _pass_file: pass_file,
+        _key_file: key_file,
+        _temp_dir: temp_dir,
+    })
+}
+
+// Keeps the exit status so that callers can tell partial transfers apart from hard errors.
+#[derive(Debug)]
+struct UtilFailed {
+    status: ExitStatus,
+    msg: String,
+}
+
+impl UtilFailed {
+    // Like rsync, idevsutil_dedup uses these codes if it ran to completion but some files failed.
+    fn partial_transfer(err: &(dyn Error + 'static)) -> bool {
+        err.downcast_ref::<Self>()
+            .is_some_and(|err| matches!(err.status.code(), Some(23 | 24)))
+    }
+
+    // Processes killed due to timeouts as well as socket, protocol stream and connection timeout
+    // errors as signalled by rsync are worth retrying, everything else will most likely fail again.
+    fn transient(err: &(dyn Error + 'static)) -> bool {
+        err.downcast_ref::<Self>()
+            .is_some_and(|err| matches!(err.status.code(), None | Some(10 | 12 | 30 | 35)))
+    }
+
+    // Listing a directory which was never backed up fails like rsync does for missing source paths.
+    fn not_found(err: &(dyn Error + 'static)) -> bool {
+        err.downcast_ref::<Self>().is_some_and(|err| {
+            err.msg
+                .to_ascii_lowercase()
+                .contains("no such file or directory")
+        })
+    }
+}
+
+impl fmt::Display for UtilFailed {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.msg)
+    }
+}
+
+impl Error for UtilFailed {}
+
+fn util_timed_out(status: ExitStatus, timeout: Duration) -> Box<dyn Error> {
+    Box::new(UtilFailed {
+        status,
+        msg: format!(
+            "idevsutil_dedup timed out after {} seconds",
+            timeout.as_secs()
+        ),
+    })
+}
+
+// Streaming callers pass no stdout as it has already been consumed by the parser.
+fn util_failed(status: ExitStatus, stdout: &[u8], stderr: &[u8]) -> Box<dyn Error> {
+    let mut msg = format!("idevsutil_dedup failed with status {status:?}");
+
+    let stderr = output_tail(&String::from_utf8_lossy(stderr), 10);
+
+    if !stderr.is_empty() {
+        msg.push_str(": ");
+        msg.push_str(&stderr);
+    }
+
+    let stdout = output_tail(&String::from_utf8_lossy(stdout), 3);
+
+    if !stdout.is_empty() {
+        msg.push_str(" (last output: ");
+        msg.push_str(&stdout);
+        msg.push(')');
+    }
+
+    Box::new(UtilFailed { status, msg })
+}
+
+fn wait_with_timeout(child: &mut Child, timeout: Option<Duration>) -> Fallible<Output> {
+    let stdout = read_to_end(child.stdout.take().unwrap());
+    let stderr = read_to_end(child.stderr.take().unwrap());
+
+    let status = match timeout {
+        Some(timeout) => wait_until(child, timeout)?,
+        None => child.wait()?,
+    };
+
+    let stdout = stdout.join().unwrap()?;
+    let stderr = stderr.join().unwrap()?;
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+fn wait_until(child: &mut Child, timeout: Duration) -> Fallible<ExitStatus> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        if Instant::now() >= deadline {
+            child.kill()?;
+            let status = child.wait()?;
+
+            return Err(util_timed_out(status, timeout));
+        }
+
+        sleep(Duration::from_millis(100));
+    }
+}
+
+fn command_timeout(config: &Config) -> Option<Duration> {
+    config.command_timeout.map(Duration::from_secs)
+}
+
+// Kills the child unless the returned sender is dropped before the timeout elapses,
+// the watchdog yielding whether it had to do so.
+fn kill_after(child: &Child, timeout: Duration) -> (Sender<()>, JoinHandle<bool>) {
+    let pid = child.id() as libc::pid_t;
+    let (done, expired) = channel();
+
+    let watchdog = spawn(move || match expired.recv_timeout(timeout) {
+        Err(RecvTimeoutError::Timeout) => {
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+
+            true
+        }
+        Ok(()) | Err(RecvTimeoutError::Disconnected) => false,
+    });
+
+    (done, watchdog)
+}
+
+fn read_to_end<R: Read + Send + 'static>(mut reader: R) -> JoinHandle<io::Result<Vec<u8>>> {
+    spawn(move || {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).map(|_| buf)
+    })
+}
+
+const MAX_TAIL_LEN: usize = 1024;
+
+fn output_tail(output: &str, max_lines: usize) -> String {
+    let lines = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    let tail = lines[lines.len().saturating_sub(max_lines)..].join(" / ");
+
+    if tail.len() <= MAX_TAIL_LEN {
+        return tail;
+    }
+
+    let mut start = tail.len() - MAX_TAIL_LEN;
+
+    while !tail.is_char_boundary(start) {
+        start += 1;
+    }
+
+    format!("...{}", &tail[start..])
+}
+
+fn get_server_ip(config: &Config) -> Fallible<String> {
+    let output = run_util(config, ["--getServerAddress", &config.username])?;
+
+    let srv_ip = parse_tree::<ServerIp>(output)?;
+
+    Ok(srv_ip.val)
+}
+
+fn list_devices(config: &Config, srv_ip: &str) -> Fallible<Vec<Device>> {
+    let output = run_util(config, ["--list-device", &account_home(config, srv_ip)])?;
+
+    parse_items(output, config.strict_parsing)
+}
+
+fn print_devices(config: &Config, json: bool) -> Fallible {
+    if config.print_args {
+        let _ = get_server_ip(config);
+        let _ = list_devices(config, "SERVER_IP");
+
+        return Ok(());
+    }
+
+    let srv_ip = get_server_ip(config).map_err(context("Failed to determine server IP"))?;
+
+    let devices = list_devices(config, &srv_ip)?;
+
+    if json {
+        println!("{}", to_json_string(&devices)?);
+    } else {
+        for device in &devices {
+            println!("{}\t{}", device.nick_name, device.device_id);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_quota(config: &Config) -> Fallible {
+    if config.print_args {
+        let _ = get_server_ip(config);
+        let _ = get_quota(config, "SERVER_IP", None);
+
+        return Ok(());
+    }
+
+    let srv_ip = get_server_ip(config).map_err(context("Failed to determine server IP"))?;
+
+    let quota_used = get_quota(config, &srv_ip, None)?;
+    let (used_size, used_unit) = format_size(quota_used, config.size_units);
+
+    match (config.quota_limit, quota_percentage(config, quota_used)) {
+        (Some(quota_limit), Some(percentage)) => {
+            let (limit_size, limit_unit) = format_size(quota_limit, config.size_units);
+
+            println!(
+                "Quota used: {used_size:.1} {used_unit} of {limit_size:.1} {limit_unit} ({percentage:.1}%)"
+            );
+        }
+        _ => println!("Quota used: {used_size:.1} {used_unit}"),
+    }
+
+    if let Some(warning) = quota_warning(config, quota_used) {
+        warn!("{warning}");
+    }
+
+    Ok(())
+}
+
+// idevsutil_dedup does not report the size of the account, hence the configured limit is used as total.
+fn quota_percentage(config: &Config, quota_used: u64) -> Option<f64> {
+    config
+        .quota_limit
+        .filter(|quota_limit| *quota_limit != 0)
+        .map(|quota_limit| 100.0 * quota_used as f64 / quota_limit as f64)
+}
+
+fn quota_warning(config: &Config, quota_used: u64) -> Option<String> {
+    let warn_percent = config.quota_warn_percent?;
+    let percentage = quota_percentage(config, quota_used)?;
+
+    if percentage < warn_percent {
+        return None;
+    }
+
+    Some(format!(
+        "WARNING: {percentage:.1}% of quota are used which exceeds the threshold of {warn_percent:.1}%!"
+    ))
+}
+
+fn get_device_id(config: &Config, srv_ip: &str) -> Fallible<String> {
+    let devices = list_devices(config, srv_ip)?;
+
+    for device in &devices {
+        if device.nick_name == config.device_name {
+            let dev_id = wrap_device_id(config, &device.device_id)?;
+
+            debug!(
+                "Using device ID {} for device {}",
+                dev_id, config.device_name
+            );
+
+            return Ok(dev_id);
+        }
+    }
+
+    let mut msg = format!(
+        "Failed to resolve device ID of {}, available devices are: {}",
+        config.device_name,
+        devices
+            .iter()
+            .map(|device| device.nick_name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    if let Some(device) = devices
+        .iter()
+        .min_by_key(|device| edit_distance(&device.nick_name, &config.device_name))
+    {
+        msg.push_str(&format!(" (did you mean {}?)", device.nick_name));
+    }
+
+    Err(msg.into())
+}
+
+// The listed ID is checked before wrapping it as a malformed one
+// would only make every later command fail without explanation.
+fn wrap_device_id(config: &Config, device_id: &str) -> Fallible<String> {
+    if !config.device_id_prefix.is_empty()
+        && !config.device_id_suffix.is_empty()
+        && device_id.starts_with(&config.device_id_prefix)
+        && device_id.ends_with(&config.device_id_suffix)
+    {
+        return Err(format!(
+            "Listed device ID {device_id} appears to be wrapped already, consider setting device_id_prefix and device_id_suffix to empty strings"
+        )
+        .into());
+    }
+
+    if device_id.is_empty() || !device_id.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(format!("Listed device ID {device_id:?} is not numeric").into());
+    }
+
+    Ok(format!(
+        "{}{}{}",
+        config.device_id_prefix, device_id, config.device_id_suffix
+    ))
+}
+
+fn edit_distance(lhs: &str, rhs: &str) -> usize {
+    let rhs = rhs.chars().collect::<Vec<_>>();
+
+    let mut prev_row = (0..=rhs.len()).collect::<Vec<_>>();
+    let mut curr_row = vec![0; rhs.len() + 1];
+
+    for (i, lhs) in lhs.chars().enumerate() {
+        curr_row[0] = i + 1;
+
+        for (j, rhs) in rhs.iter().enumerate() {
+            let cost = if lhs == *rhs { 0 } else { 1 };
+
+            curr_row[j + 1] = (prev_row[j] + cost)
+                .min(prev_row[j + 1] + 1)
+                .min(curr_row[j] + 1);
+        }
+
+        swap(&mut prev_row, &mut curr_row);
+    }
+
+    prev_row[rhs.len()]
+}
+
+fn get_quota(config: &Config, srv_ip: &str, timeout: Option<Duration>) -> Fallible<u64> {
+    let output = run_util_with_timeout(
+        config,
+        ["--calc-quota", &account_home(config, srv_ip)],
+        timeout,
+    )?;
+
+    let items = parse_items::<Quota>(output, config.strict_parsing)?;
+
+    for item in items {
+        if let Some(quota_used) = item.quota_used {
+            if let Some(quota_used) = quota_used.strip_suffix(" Bytes") {
+                let quota_used = quota_used.parse()?;
+
+                return Ok(quota_used);
+            }
+        }
+    }
+
+    Err("Failed to parse quota items".into())
+}
+
+fn list_dir(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    dir: &Path,
+) -> Fallible<impl Iterator<Item = (PathBuf, bool)>> {
+    let entries = list_remote_dir(config, srv_ip, dev_id, dir)?;
+
+    Ok(entries.into_iter().map(|entry| (entry.name, entry.is_dir)))
+}
+
+struct RemoteEntry {
+    name: PathBuf,
+    is_dir: bool,
+    size: Option<u64>,
+    mtime: Option<i64>,
+}
+
+fn list_remote_dir(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    dir: &Path,
+) -> Fallible<Vec<RemoteEntry>> {
+    let server_path = server_path(config, srv_ip, dir);
+    let device_id = make_arg("--device-id=", dev_id);
+
+    let args = [
+        OsStr::new("--auth-list"),
+        OsStr::new("--xml-output"),
+        &device_id,
+        &server_path,
+    ];
+
+    // Listings can be huge, hence items are converted as they arrive instead of buffering
+    // the whole output. As they have no side effects, failed listings are simply repeated.
+    retry_transient(config, &format!("listing of {}", dir.display()), || {
+        let mut entries = Vec::new();
+
+        run_util_streaming(config, args, |resource: Resource| {
+            entries.extend(remote_entry(resource));
+
+            Ok(())
+        })?;
+
+        Ok(entries)
+    })
+}
+
+// Directories which were never backed up are treated as empty, but other failures are not.
+fn list_remote_dir_or_empty(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    dir: &Path,
+) -> Fallible<Vec<RemoteEntry>> {
+    match list_remote_dir(config, srv_ip, dev_id, dir) {
+        Err(err) if UtilFailed::not_found(&*err) => Ok(Vec::new()),
+        res => res,
+    }
+}
+
+fn remote_entry(resource: Resource) -> Option<RemoteEntry> {
+    let is_dir = match resource.type_ {
+        'D' => true,
+        'F' => false,
+        type_ => {
+            warn!("Skipping unknown resource type: {type_}");
+
+            return None;
+        }
+    };
+
+    Some(RemoteEntry {
+        name: resource.name,
+        is_dir,
+        size: resource.size.and_then(|size| size.parse().ok()),
+        mtime: resource.mod_time.and_then(|mtime| mtime.parse().ok()),
+    })
+}
+
+fn walk_dir<F: FnMut(PathBuf, Option<u64>, Option<i64>) -> Fallible<Option<PathBuf>>>(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    dir: &Path,
+    mut f: F,
+) -> Fallible {
+    if config.list_concurrency > 1 {
+        return walk_dir_concurrently(config, srv_ip, dev_id, dir, f);
+    }
+
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in list_remote_dir(config, srv_ip, dev_id, &dir)? {
+            if interrupted() {
+                info!("Stopping as interrupted...");
+
+                return Ok(());
+            }
+
+            let path = dir.join(entry.name);
+
+            if let Some(path) = f(path, entry.size, entry.mtime)? {
+                if entry.is_dir {
+                    dirs.push(path);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Only the listings are performed by the worker threads whereas the callback is still invoked
+// on the calling thread. Directories are dispatched only as workers become idle, so that
+// stopping early does not wait for a backlog of listings.
+fn walk_dir_concurrently<
+    F: FnMut(PathBuf, Option<u64>, Option<i64>) -> Fallible<Option<PathBuf>>,
+>(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    dir: &Path,
+    mut f: F,
+) -> Fallible {
+    let (job_tx, job_rx) = channel::<PathBuf>();
+    let job_rx = Mutex::new(job_rx);
+    let (res_tx, res_rx) = channel();
+
+    scope(|scope| {
+        for _ in 0..config.list_concurrency {
+            let job_rx = &job_rx;
+            let res_tx = res_tx.clone();
+
+            scope.spawn(move || {
+                loop {
+                    // The lock must not be held while listing the directory.
+                    let dir = match job_rx.lock().unwrap().recv() {
+                        Ok(dir) => dir,
+                        Err(_) => break,
+                    };
+
+                    let entries = list_remote_dir(config, srv_ip, dev_id, &dir)
+                        .map_err(|err| err.to_string());
+
+                    if res_tx.send((dir, entries)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let mut walk = || -> Fallible {
+            let mut dirs = vec![dir.to_path_buf()];
+            let mut in_flight = 0;
+
+            loop {
+                while in_flight < config.list_concurrency {
+                    match dirs.pop() {
+                        Some(dir) => job_tx.send(dir)?,
+                        None => break,
+                    }
+
+                    in_flight += 1;
+                }
+
+                if in_flight == 0 {
+                    return Ok(());
+                }
+
+                let (dir, entries) = res_rx.recv()?;
+                in_flight -= 1;
+
+                for entry in entries? {
+                    if interrupted() {
+                        info!("Stopping as interrupted...");
+
+                        return Ok(());
+                    }
+
+                    let path = dir.join(entry.name);
+
+                    if let Some(path) = f(path, entry.size, entry.mtime)? {
+                        if entry.is_dir {
+                            dirs.push(path);
+                        }
+                    }
+                }
+            }
+        };
+
+        let res = walk();
+
+        // Disconnecting the job queue lets idle workers exit.
+        drop(job_tx);
+
+        res
+    })
+}
+
+fn archive_path(config: &Config, path: &Path) -> Option<PathBuf> {
+    let path = path.strip_prefix(&config.relative_root).ok()?;
+
+    Some(Path::new("/").join(path))
+}
+
+// With case-insensitive excludes, the whole path is lowercased and not just its final component,
+// so that e.g. an exclude of /home/me/Downloads also matches /Home/Me/downloads/file.
+//
+// The excludes of an include root apply in addition to the global ones to all paths below it.
+fn find_exclude<'a>(config: &'a Config, path: &Path) -> Option<&'a PathBuf> {
+    let mut excludes = config.excludes.iter().chain(
+        config
+            .root_excludes
+            .iter()
+            .filter(|(root, _)| path.starts_with(root))
+            .flat_map(|(_, excludes)| excludes),
+    );
+
+    if !config.case_insensitive_excludes {
+        return excludes.find(|exclude| exclude_matches(exclude, path));
+    }
+
+    let path = PathBuf::from(path.to_string_lossy().to_lowercase());
+
+    excludes.find(|exclude| {
+        exclude_matches(Path::new(&exclude.to_string_lossy().to_lowercase()), &path)
+    })
+}
+
+// Hidden directories are pruned as a whole since they are not descended into. The includes themselves are never hidden.
+fn hidden_path(config: &Config, path: &Path, depth: usize) -> bool {
+    config.skip_hidden
+        && depth != 0
+        && path
+            .file_name()
+            .is_some_and(|name| name.as_bytes().starts_with(b"."))
+}
+
+// Symbolic links below the includes are skipped before they are canonicalized if they should not be followed,
+// hence neither they nor their targets are matched against the excludes. The includes themselves are always followed.
+fn unfollowed_symlink(config: &Config, path: &Path, depth: usize) -> bool {
+    if config.follow_symlinks || depth == 0 {
+        return false;
+    }
+
+    if !path
+        .symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+    {
+        return false;
+    }
+
+    warn!(
+        "Skipping path {} as it is a symbolic link which is not followed",
+        path.display()
+    );
+
+    true
+}
+
+// Plain excludes are prefixes while glob patterns are matched against the path and all its ancestors,
+// so that excluding a directory by pattern also excludes its contents. Relative patterns like
+// `*.tmp` or `node_modules` match at any depth.
+fn exclude_matches(exclude: &Path, path: &Path) -> bool {
+    if !is_glob(exclude) {
+        return path.starts_with(exclude);
+    }
+
+    let pattern = exclude.to_string_lossy();
+
+    let pattern = if pattern.starts_with('/') {
+        pattern
+    } else {
+        format!("**/{pattern}").into()
+    };
+
+    path.ancestors()
+        .any(|ancestor| glob_match(&pattern, ancestor))
+}
+
+fn is_glob(exclude: &Path) -> bool {
+    exclude
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .any(|byte| matches!(byte, b'*' | b'?'))
+}
+
+fn local_path(config: &Config, path: &Path) -> PathBuf {
+    config
+        .relative_root
+        .join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn list_command() -> Subcommand {
+    Subcommand::new("list")
+        .arg(
+            Arg::new("sub_dir")
+                .long("sub-dir")
+                .value_parser(value_parser!(PathBuf))
+                .default_value("/"),
+        )
+        .arg(
+            Arg::new("export")
+                .long("export")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_parser(["csv", "json", "plain"])
+                .default_value("csv"),
+        )
+        .arg(
+            Arg::new("recursive")
+                .long("recursive")
+                .action(ArgAction::SetTrue),
+        )
+}
+
+fn restore_command() -> Subcommand {
+    Subcommand::new("restore")
+        .arg(
+            Arg::new("sub_dir")
+                .long("sub-dir")
+                .value_parser(value_parser!(PathBuf))
+                .default_value("/"),
+        )
+        .arg(
+            Arg::new("file")
+                .long("file")
+                .value_parser(value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .conflicts_with_all(["sub_dir", "missing"]),
+        )
+        .arg(
+            Arg::new("glob")
+                .long("glob")
+                .conflicts_with_all(["file", "missing"]),
+        )
+        .arg(
+            Arg::new("out_dir")
+                .long("out-dir")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("missing")
+                .long("missing")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("restore_metadata")
+                .long("restore-metadata")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(Arg::new("atomic").long("atomic").action(ArgAction::SetTrue))
+        .arg(Arg::new("force").long("force").action(ArgAction::SetTrue))
+        .arg(
+            Arg::new("strip_components")
+                .long("strip-components")
+                .value_parser(value_parser!(usize))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("path_map")
+                .long("path-map")
+                .value_parser(parse_path_map)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("no_verify_size")
+                .long("no-verify-size")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("manifest")
+                .long("manifest")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("jobs")
+                .long("jobs")
+                .value_parser(value_parser!(usize))
+                .default_value("1"),
+        )
+}
+
+fn run_restore(config: &Config, srv_ip: &str, dev_id: &str, matches: &ArgMatches) -> Fallible {
+    let sub_dir = matches.get_one::<PathBuf>("sub_dir").unwrap();
+    let files = matches.get_many::<PathBuf>("file");
+    let missing = matches.get_flag("missing");
+    let opts = RestoreOptions {
+        restore_metadata: matches.get_flag("restore_metadata"),
+        atomic: matches.get_flag("atomic"),
+        force: matches.get_flag("force"),
+        strip_components: *matches.get_one::<usize>("strip_components").unwrap(),
+        path_map: matches
+            .get_many::<(PathBuf, PathBuf)>("path_map")
+            .map_or_else(Vec::new, |path_map| path_map.cloned().collect()),
+        no_verify_size: matches.get_flag("no_verify_size"),
+        manifest: matches.get_one::<PathBuf>("manifest").cloned(),
+        jobs: *matches.get_one::<usize>("jobs").unwrap(),
+        transferred: Default::default(),
+    };
+
+    // Individual files are restored in place unless an output directory is given.
+    if let Some(files) = files {
+        let files = files.cloned().collect::<Vec<_>>();
+        let out_dir = matches
+            .get_one::<PathBuf>("out_dir")
+            .unwrap_or(&config.relative_root);
+
+        return restore_files(config, srv_ip, dev_id, &files, out_dir, &opts);
+    }
+
+    let out_dir = matches
+        .get_one::<PathBuf>("out_dir")
+        .ok_or("Missing --out-dir for restore")?;
+
+    if let Some(pattern) = matches.get_one::<String>("glob") {
+        restore_glob(config, srv_ip, dev_id, sub_dir, pattern, out_dir, &opts)
+    } else if missing {
+        install_interrupt_handler();
+
+        restore_missing(config, srv_ip, dev_id, sub_dir, out_dir, &opts)
+    } else {
+        restore(config, srv_ip, dev_id, sub_dir, out_dir, &opts)
+    }
+}
+
+// Both sides are relative to the root of the archive respectively the output directory.
+fn parse_path_map(val: &str) -> Result<(PathBuf, PathBuf), String> {
+    let (from, to) = val
+        .split_once('=')
+        .ok_or_else(|| format!("Path mapping {val} is not of the form <from>=<to>"))?;
+
+    let from = Path::new(from.trim_start_matches('/')).to_path_buf();
+    let to = Path::new(to.trim_start_matches('/')).to_path_buf();
+
+    Ok((from, to))
+}
+
+fn parse_duration(val: &str) -> Result<Duration, String> {
+    let (val, unit) = match val.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => val.split_at(pos),
+        None => (val, "s"),
+    };
+
+    let val = val.parse::<u64>().map_err(|err| err.to_string())?;
+
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        unit => return Err(format!("Unknown unit of duration: {unit}")),
+    };
+
+    let secs = val
+        .checked_mul(factor)
+        .ok_or_else(|| format!("Duration is too long: {val}{unit}"))?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+// Either an RFC 3339 timestamp or a duration counted back from now, as seconds since the epoch.
+fn parse_since(val: &str) -> Result<i64, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(val) {
+        return Ok(time.timestamp());
+    }
+
+    let age = parse_duration(val)
+        .map_err(|err| format!("Neither an RFC 3339 timestamp nor a duration: {err}"))?;
+
+    i64::try_from(age.as_secs())
+        .ok()
+        .and_then(|age| Local::now().timestamp().checked_sub(age))
+        .ok_or_else(|| format!("Duration is too long: {val}"))
+}
+
+// Stored paths live below an optional prefix to keep multiple hosts apart.
+// The home directory is always given with a trailing slash, paths below it never are.
+fn server_path(config: &Config, srv_ip: &str, path: &Path) -> OsString {
+    let mut arg = OsString::from(account_home(config, srv_ip));
+
+    if let Some(prefix) = &config.path_prefix {
+        let prefix = prefix.trim_matches('/');
+
+        if !prefix.is_empty() {
+            arg.push(prefix);
+            arg.push("/");
+        }
+    }
+
+    arg.push(path.strip_prefix("/").unwrap_or(path));
+
+    arg
+}
+
+fn remote_home(config: &Config, srv_ip: &str) -> OsString {
+    server_path(config, srv_ip, Path::new("/"))
+}
+
+// Account-wide operations like listing devices or calculating quota ignore the path prefix.
+fn account_home(config: &Config, srv_ip: &str) -> String {
+    format!("{}@{}::home/", config.username, srv_ip)
+}
+
+// Temporary files are recognizable by their prefix so that only they
+// and not the whole temporary directory are excluded from the backup.
+const TEMP_PREFIX: &str = ".idrive_backup";
+
+fn new_temp_file() -> io::Result<NamedTempFile> {
+    Builder::new().prefix(TEMP_PREFIX).tempfile()
+}
+
+fn new_temp_dir() -> io::Result<TempDir> {
+    Builder::new().prefix(TEMP_PREFIX).tempdir()
+}
+
+fn make_arg<S: AsRef<OsStr>>(pre: &str, val: S) -> OsString {
+    let mut arg = OsString::new();
+    arg.push(pre);
+    arg.push(val);
+    arg
+}
 
 fn get_hostname() -> Fallible<String> {
     let mut hostname = String::from_utf8(Command::new("hostname").output()?.stdout)?;
@@ -347,24 +2601,41 @@ fn get_hostname() -> Fallible<String> {
     Ok(hostname)
 }
 
-#[allow(clippy::useless_let_if_seq)]
-fn format_size(size: u64) -> (f64, &'static str) {
-    let mut size = size as f64;
-    let mut unit = "B";
-
-    if size > 1024.0 {
-        size /= 1024.0;
-        unit = "kB";
+// Without a terminal to answer, prompts are declined instead of blocking unattended runs.
+fn confirm(config: &Config, question: &str) -> Fallible<bool> {
+    if config.assume_yes {
+        return Ok(true);
     }
 
-    if size > 1024.0 {
-        size /= 1024.0;
-        unit = "MB";
+    if !stdin().is_terminal() {
+        return Ok(false);
     }
 
-    if size > 1024.0 {
-        size /= 1024.0;
-        unit = "GB";
+    eprint!("{question} [y/N] ");
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// Binary sizes use IEC prefixes so that they cannot be mistaken for their SI counterparts.
+fn format_size(size: u64, units: SizeUnits) -> (f64, &'static str) {
+    let (base, units) = match units {
+        SizeUnits::Binary => (1024.0, ["B", "KiB", "MiB", "GiB", "TiB", "PiB"]),
+        SizeUnits::Si => (1000.0, ["B", "kB", "MB", "GB", "TB", "PB"]),
+    };
+
+    let mut size = size as f64;
+    let mut unit = units[0];
+
+    for next_unit in &units[1..] {
+        if size < base {
+            break;
+        }
+
+        size /= base;
+        unit = next_unit;
     }
 
     (size, unit)
@@ -375,3 +2646,515 @@ type Fallible<T = ()> = Result<T, Box<dyn Error>>;
 fn context(msg: &'static str) -> impl FnOnce(Box<dyn Error>) -> Box<dyn Error> {
     move |err| format!("{msg}: {err}").into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, write};
+    use std::os::unix::fs::symlink;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tempfile::TempDir;
+
+    use super::test_util::{config, loaded_config, FakeUtil};
+
+    #[test]
+    fn format_size_steps_through_binary_units() {
+        assert_eq!(format_size(0, SizeUnits::Binary), (0.0, "B"));
+        assert_eq!(format_size(1023, SizeUnits::Binary), (1023.0, "B"));
+        assert_eq!(format_size(1024, SizeUnits::Binary), (1.0, "KiB"));
+        assert_eq!(format_size(1024 * 1024, SizeUnits::Binary), (1.0, "MiB"));
+        assert_eq!(format_size(3 << 40, SizeUnits::Binary), (3.0, "TiB"));
+        assert_eq!(format_size(2048 << 50, SizeUnits::Binary), (2048.0, "PiB"));
+    }
+
+    #[test]
+    fn format_size_steps_through_si_units() {
+        assert_eq!(format_size(999, SizeUnits::Si), (999.0, "B"));
+        assert_eq!(format_size(1000, SizeUnits::Si), (1.0, "kB"));
+        assert_eq!(format_size(1024, SizeUnits::Si), (1.024, "kB"));
+        assert_eq!(format_size(1_500_000, SizeUnits::Si), (1.5, "MB"));
+        assert_eq!(format_size(3_000_000_000_000, SizeUnits::Si), (3.0, "TB"));
+    }
+
+    #[test]
+    fn server_path_joins_account_home_and_path() {
+        let config = config("");
+
+        assert_eq!(account_home(&config, "1.2.3.4"), "user@1.2.3.4::home/");
+        assert_eq!(remote_home(&config, "1.2.3.4"), "user@1.2.3.4::home/");
+        assert_eq!(
+            server_path(&config, "1.2.3.4", Path::new("/home/me/notes.txt")),
+            "user@1.2.3.4::home/home/me/notes.txt"
+        );
+        assert_eq!(
+            server_path(&config, "1.2.3.4", Path::new("home/me")),
+            "user@1.2.3.4::home/home/me"
+        );
+    }
+
+    #[test]
+    fn server_path_inserts_path_prefix() {
+        let config = config("path_prefix: /backups/laptop/");
+
+        assert_eq!(account_home(&config, "1.2.3.4"), "user@1.2.3.4::home/");
+        assert_eq!(
+            remote_home(&config, "1.2.3.4"),
+            "user@1.2.3.4::home/backups/laptop/"
+        );
+        assert_eq!(
+            server_path(&config, "1.2.3.4", Path::new("/home/me/notes.txt")),
+            "user@1.2.3.4::home/backups/laptop/home/me/notes.txt"
+        );
+
+        let config = super::test_util::config("path_prefix: /");
+
+        assert_eq!(remote_home(&config, "1.2.3.4"), "user@1.2.3.4::home/");
+    }
+
+    #[test]
+    fn read_config_canonicalizes_excludes() {
+        let dir = TempDir::new().unwrap();
+        let data = dir.path().canonicalize().unwrap().join("data");
+        create_dir_all(data.join("cache")).unwrap();
+        symlink(&data, dir.path().join("link")).unwrap();
+
+        let config = loaded_config(&format!(
+            "includes: []\nexcludes: [{}/link/cache/, '*.tmp']",
+            dir.path().display()
+        ));
+
+        assert_eq!(
+            config.excludes,
+            [data.join("cache"), PathBuf::from("*.tmp")]
+        );
+
+        assert!(find_exclude(&config, &data.join("cache/file")).is_some());
+        assert!(find_exclude(&config, &data.join("other")).is_none());
+    }
+
+    #[test]
+    fn read_config_accepts_flat_includes() {
+        let config = loaded_config(
+            "includes: [/nonexistent/a, /nonexistent/b]\nexcludes: /nonexistent/a/tmp",
+        );
+
+        assert_eq!(
+            config.includes,
+            [Path::new("/nonexistent/a"), Path::new("/nonexistent/b")]
+        );
+        assert_eq!(config.excludes, [Path::new("/nonexistent/a/tmp")]);
+        assert!(config.root_excludes.is_empty());
+    }
+
+    #[test]
+    fn read_config_accepts_includes_with_their_own_excludes() {
+        let config = loaded_config(
+            r#"
+includes:
+- /nonexistent/photos
+- path: /nonexistent/src
+  excludes: [target, "*.o"]
+excludes: [/nonexistent/photos/tmp]
+"#,
+        );
+
+        assert_eq!(
+            config.includes,
+            [
+                Path::new("/nonexistent/photos"),
+                Path::new("/nonexistent/src")
+            ]
+        );
+        assert_eq!(
+            config.root_excludes,
+            [(
+                PathBuf::from("/nonexistent/src"),
+                vec![
+                    PathBuf::from("/nonexistent/src/target"),
+                    PathBuf::from("*.o")
+                ]
+            )]
+        );
+
+        let excluded = |path: &str| find_exclude(&config, Path::new(path)).is_some();
+
+        assert!(excluded("/nonexistent/src/target/debug"));
+        assert!(excluded("/nonexistent/src/main.o"));
+        assert!(excluded("/nonexistent/photos/tmp"));
+        assert!(!excluded("/nonexistent/photos/target"));
+        assert!(!excluded("/nonexistent/photos/main.o"));
+        assert!(!excluded("/nonexistent/src/main.rs"));
+    }
+
+    #[test]
+    fn hidden_path_matches_hidden_files_and_directories_below_includes() {
+        let mut config = config("");
+        assert!(!hidden_path(&config, Path::new("/home/me/.profile"), 1));
+
+        config.skip_hidden = true;
+        assert!(hidden_path(&config, Path::new("/home/me/.profile"), 1));
+        assert!(hidden_path(&config, Path::new("/home/me/.cache"), 1));
+        assert!(!hidden_path(&config, Path::new("/home/me/notes.txt"), 1));
+        // The contents of hidden directories are pruned as the directories are not descended into.
+        assert!(!hidden_path(
+            &config,
+            Path::new("/home/me/.cache/notes.txt"),
+            2
+        ));
+        assert!(!hidden_path(&config, Path::new("/home/me/.config"), 0));
+    }
+
+    #[test]
+    fn unfollowed_symlink_skips_links_below_includes() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("file");
+        let file_link = dir.path().join("file_link");
+        let dir_link = dir.path().join("dir_link");
+        write(&file, "contents").unwrap();
+        symlink(&file, &file_link).unwrap();
+        symlink("/tmp", &dir_link).unwrap();
+
+        let mut config = config("");
+        assert!(config.follow_symlinks);
+        assert!(!unfollowed_symlink(&config, &file_link, 1));
+        assert!(!unfollowed_symlink(&config, &dir_link, 1));
+
+        config.follow_symlinks = false;
+        assert!(unfollowed_symlink(&config, &file_link, 1));
+        assert!(unfollowed_symlink(&config, &dir_link, 2));
+        assert!(!unfollowed_symlink(&config, &file, 1));
+        assert!(!unfollowed_symlink(&config, &dir_link, 0));
+    }
+
+    #[test]
+    fn archive_path_round_trips_with_default_relative_root() {
+        let config = config("");
+
+        let path = Path::new("/home/user/file");
+
+        let archive_path = archive_path(&config, path).unwrap();
+        assert_eq!(archive_path, Path::new("/home/user/file"));
+
+        assert_eq!(local_path(&config, &archive_path), path);
+    }
+
+    #[test]
+    fn archive_path_round_trips_with_relative_root() {
+        let config = config("relative_root: /home/user");
+
+        let path = Path::new("/home/user/docs/file");
+
+        let archive_path = archive_path(&config, path).unwrap();
+        assert_eq!(archive_path, Path::new("/docs/file"));
+
+        assert_eq!(local_path(&config, &archive_path), path);
+    }
+
+    #[test]
+    fn archive_path_rejects_paths_outside_relative_root() {
+        let config = config("relative_root: /home/user");
+
+        assert_eq!(archive_path(&config, Path::new("/home/other/file")), None);
+        assert_eq!(archive_path(&config, Path::new("/home/username")), None);
+    }
+
+    #[test]
+    fn wrap_device_id_wraps_numeric_ids() {
+        let config = config("");
+
+        assert_eq!(
+            wrap_device_id(&config, "123456789").unwrap(),
+            "5c0b1234567894b5z"
+        );
+    }
+
+    #[test]
+    fn wrap_device_id_uses_configured_prefix_and_suffix() {
+        let config = config("device_id_prefix: ''\ndevice_id_suffix: ''");
+
+        assert_eq!(wrap_device_id(&config, "123").unwrap(), "123");
+    }
+
+    #[test]
+    fn wrap_device_id_rejects_malformed_ids() {
+        let config = config("");
+
+        assert!(wrap_device_id(&config, "").is_err());
+        assert!(wrap_device_id(&config, "12ab34").is_err());
+        assert!(wrap_device_id(&config, "12 34").is_err());
+        assert!(wrap_device_id(&config, "12-34").is_err());
+    }
+
+    #[test]
+    fn wrap_device_id_rejects_wrapped_ids() {
+        let config = config("");
+
+        let err = wrap_device_id(&config, "5c0b1234567894b5z").unwrap_err();
+
+        assert!(err.to_string().contains("wrapped already"));
+    }
+
+    #[test]
+    fn util_failed_detects_partial_transfers() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let failed = |code: i32| util_failed(ExitStatus::from_raw(code << 8), &[], b"error");
+
+        assert!(UtilFailed::partial_transfer(&*failed(23)));
+        assert!(UtilFailed::partial_transfer(&*failed(24)));
+        assert!(!UtilFailed::partial_transfer(&*failed(5)));
+        assert!(!UtilFailed::partial_transfer(&*failed(12)));
+        assert!(!UtilFailed::partial_transfer(&*Box::<dyn Error>::from(
+            "idevsutil_dedup timed out after 60 seconds"
+        )));
+    }
+
+    #[test]
+    fn parse_duration_applies_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("3h"), Ok(Duration::from_secs(3 * 3600)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+        assert!(parse_duration("7w").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflow() {
+        assert!(parse_duration(&format!("{}m", u64::MAX / 60 + 1)).is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX / 3600 + 1)).is_err());
+        assert!(parse_duration(&format!("{}d", u64::MAX / 86400 + 1)).is_err());
+        assert!(parse_duration(&format!("{}d", u64::MAX / 86400)).is_ok());
+        assert!(parse_since(&format!("{}s", u64::MAX)).is_err());
+    }
+
+    fn restore_matches(args: &[&str]) -> ArgMatches {
+        restore_command()
+            .try_get_matches_from([&["restore"], args].concat())
+            .unwrap()
+    }
+
+    #[test]
+    fn restore_command_passes_sub_dir_and_out_dir() {
+        let out_dir = TempDir::new().unwrap();
+        let out_dir_arg = out_dir.path().to_str().unwrap();
+
+        let mut config = config("");
+        let fake = FakeUtil::install(&mut config, |invocation| {
+            if invocation.has_arg("--auth-list") {
+                let output = if invocation.server_path() == "user@1.2.3.4::home/home/me/documents" {
+                    r#"<item restype="F" fname="notes.txt" size="10"/>"#
+                } else {
+                    ""
+                };
+
+                return (output.to_owned(), 0);
+            }
+
+            (
+                r#"<item per="100%" fname="home/me/documents/notes.txt" tottrf_sz="10"/>"#
+                    .to_owned(),
+                0,
+            )
+        });
+
+        let matches = restore_matches(&[
+            "--sub-dir",
+            "/home/me/documents",
+            "--out-dir",
+            out_dir_arg,
+            "--no-verify-size",
+        ]);
+
+        run_restore(&config, "1.2.3.4", "5c0b123", &matches).unwrap();
+
+        let invocations = fake.invocations();
+        assert_eq!(invocations.len(), 2);
+        assert_eq!(
+            invocations[0].server_path(),
+            "user@1.2.3.4::home/home/me/documents"
+        );
+        assert_eq!(invocations[1].files_from, ["/home/me/documents/notes.txt"]);
+        assert_eq!(invocations[1].args.last().unwrap(), out_dir_arg);
+    }
+
+    #[test]
+    fn restore_command_defaults_to_archive_root() {
+        let out_dir = TempDir::new().unwrap();
+
+        let mut config = config("");
+        let fake = FakeUtil::install(&mut config, |_invocation| (String::new(), 0));
+
+        let matches = restore_matches(&["--out-dir", out_dir.path().to_str().unwrap()]);
+
+        run_restore(&config, "1.2.3.4", "5c0b123", &matches).unwrap();
+
+        assert_eq!(fake.invocations()[0].server_path(), "user@1.2.3.4::home/");
+    }
+
+    #[test]
+    fn restore_command_requires_out_dir() {
+        let mut config = config("");
+        let fake = FakeUtil::install(&mut config, |_invocation| (String::new(), 0));
+
+        let matches = restore_matches(&["--sub-dir", "/home/me/documents"]);
+
+        let err = run_restore(&config, "1.2.3.4", "5c0b123", &matches).unwrap_err();
+
+        assert!(err.to_string().contains("--out-dir"));
+        assert!(fake.invocations().is_empty());
+    }
+
+    #[test]
+    fn list_command_does_not_descend_by_default() {
+        let matches = list_command().try_get_matches_from(["list"]).unwrap();
+        assert!(!matches.get_flag("recursive"));
+
+        let matches = list_command()
+            .try_get_matches_from(["list", "--recursive"])
+            .unwrap();
+        assert!(matches.get_flag("recursive"));
+    }
+
+    #[test]
+    fn list_remote_dir_retries_transient_failures() {
+        let attempts = AtomicUsize::new(0);
+
+        let mut config = config("retry_base_delay: 0s");
+        let fake = FakeUtil::install(&mut config, move |_invocation| {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => ("rsync error: error in socket IO (code 10)".to_owned(), 10),
+                _ => (r#"<item restype="F" fname="notes.txt"/>"#.to_owned(), 0),
+            }
+        });
+
+        let entries = list_remote_dir(&config, "1.2.3.4", "5c0b123", Path::new("/home")).unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(fake.invocations().len(), 2);
+    }
+
+    #[test]
+    fn list_remote_dir_or_empty_accepts_missing_directories() {
+        let mut config = config("retry_base_delay: 0s");
+        let fake = FakeUtil::install(&mut config, |_invocation| {
+            (
+                r#"rsync: link_stat "/home/missing" failed: No such file or directory (2)"#
+                    .to_owned(),
+                23,
+            )
+        });
+
+        let dir = Path::new("/home/missing");
+
+        assert!(list_remote_dir(&config, "1.2.3.4", "5c0b123", dir).is_err());
+        assert!(list_remote_dir_or_empty(&config, "1.2.3.4", "5c0b123", dir)
+            .unwrap()
+            .is_empty());
+
+        // Neither listing was retried.
+        assert_eq!(fake.invocations().len(), 2);
+    }
+
+    #[test]
+    fn list_remote_dir_or_empty_propagates_other_failures() {
+        let mut config = config("retry_base_delay: 0s");
+        let fake = FakeUtil::install(&mut config, |_invocation| {
+            ("@ERROR: auth failed on module".to_owned(), 5)
+        });
+
+        assert!(
+            list_remote_dir_or_empty(&config, "1.2.3.4", "5c0b123", Path::new("/home")).is_err()
+        );
+        assert_eq!(fake.invocations().len(), 1);
+    }
+
+    #[test]
+    fn run_util_gives_up_after_max_retries() {
+        let mut config = config("retry_base_delay: 0s\nmax_retries: 2");
+        let fake = FakeUtil::install(&mut config, |_invocation| (String::new(), 30));
+
+        assert!(run_util(&config, ["--list-device"]).is_err());
+        assert_eq!(fake.invocations().len(), 3);
+    }
+
+    #[test]
+    fn run_util_with_timeout_bounds_all_attempts() {
+        let mut config = config("retry_base_delay: 2s\nmax_retries: 3");
+        let fake = FakeUtil::install(&mut config, |_invocation| (String::new(), 30));
+
+        let start = Instant::now();
+
+        assert!(get_quota(&config, "1.2.3.4", Some(Duration::from_secs(1))).is_err());
+
+        // The first retry would only start after the deadline.
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(fake.invocations().len(), 1);
+    }
+
+    #[test]
+    fn run_util_with_timeout_retries_within_deadline() {
+        let attempts = AtomicUsize::new(0);
+
+        let mut config = config("retry_base_delay: 0s");
+        let fake = FakeUtil::install(&mut config, move |_invocation| {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => (String::new(), 30),
+                _ => (r#"<item quota_used="1234 Bytes"/>"#.to_owned(), 0),
+            }
+        });
+
+        assert_eq!(
+            get_quota(&config, "1.2.3.4", Some(Duration::from_secs(10))).unwrap(),
+            1234
+        );
+        assert_eq!(fake.invocations().len(), 2);
+    }
+
+    #[test]
+    fn util_process_keeps_temporary_files_until_child_has_exited() {
+        let dir = TempDir::new().unwrap();
+        let util = dir.path().join("idevsutil_dedup");
+        let seen = dir.path().join("seen");
+        let pid = dir.path().join("pid");
+
+        // The script is written by a separate process so that no file descriptor of ours
+        // is open for writing when another test forks and executes it.
+        let script = format!(
+            "#!/bin/sh\nsleep 0.2\ncat \"${{1#--password-file=}}\" > {0}\necho $$ > {1}.tmp\nmv {1}.tmp {1}\nexec sleep 60\n",
+            seen.display(),
+            pid.display()
+        );
+        let status = Command::new("sh")
+            .args(["-c", "printf '%s' \"$1\" > \"$0\" && chmod +x \"$0\""])
+            .arg(&util)
+            .arg(&script)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut config = config("");
+        config.password = "secret".to_owned();
+        config.idevsutil_path = util;
+
+        let process = spawn_util(&config, 0, &[]).unwrap();
+        let pass_file = process._pass_file.path().to_owned();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !pid.exists() && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+
+        // Dropping the process as on an early return kills and reaps the child before removing its files.
+        drop(process);
+
+        assert!(!pass_file.exists());
+        assert_eq!(read_to_string(&seen).unwrap(), "secret");
+
+        let pid = read_to_string(&pid).unwrap();
+        assert!(!Path::new("/proc").join(pid.trim()).exists());
+    }
+}
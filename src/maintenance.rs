@@ -0,0 +1,109 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::fs::{read_dir, remove_file};
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::SystemTime;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{parse_duration, Config, Fallible};
+
+// Applies to all regular files directly within the directory, e.g. logs written by cron jobs.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct Retention {
+    dir: PathBuf,
+    max_age: String,
+    compress_after: Option<String>,
+}
+
+pub fn maintenance(config: &Config) -> Fallible {
+    let retentions = [
+        ("logs", &config.log_retention),
+        ("reports", &config.report_retention),
+    ];
+
+    if retentions.iter().all(|(_, retention)| retention.is_none()) {
+        info!("Neither log_retention nor report_retention is configured, nothing to do");
+
+        return Ok(());
+    }
+
+    for (kind, retention) in retentions {
+        if let Some(retention) = retention {
+            trim(kind, retention)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn trim(kind: &str, retention: &Retention) -> Fallible {
+    let max_age = parse_duration(&retention.max_age)?;
+    let compress_after = retention
+        .compress_after
+        .as_deref()
+        .map(parse_duration)
+        .transpose()?;
+
+    let now = SystemTime::now();
+
+    let mut removed = 0;
+    let mut compressed = 0;
+
+    for entry in read_dir(&retention.dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+
+        if age > max_age {
+            remove_file(&path)?;
+
+            removed += 1;
+        } else if compress_after.is_some_and(|compress_after| age > compress_after)
+            && path.extension().is_none_or(|extension| extension != "gz")
+        {
+            // gzip keeps the modification time, so that compressed files still expire.
+            let status = Command::new("gzip").arg("--force").arg(&path).status()?;
+
+            if !status.success() {
+                return Err(format!("Failed to compress {} using gzip", path.display()).into());
+            }
+
+            compressed += 1;
+        }
+    }
+
+    info!(
+        "Removed {} and compressed {} {} in {}",
+        removed,
+        compressed,
+        kind,
+        retention.dir.display()
+    );
+
+    Ok(())
+}
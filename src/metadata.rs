@@ -0,0 +1,418 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::env::current_dir;
+use std::ffi::{OsStr, OsString};
+use std::fs::{create_dir_all, set_permissions, File, Metadata, Permissions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::from_utf8;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::xattrs::{read_xattrs, write_xattr};
+use super::{archive_path, Config, Fallible};
+
+pub struct ManifestWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl ManifestWriter {
+    pub fn create(path: &Path) -> Fallible<Self> {
+        let writer = BufWriter::new(File::create(path)?);
+
+        Ok(Self {
+            path: path.canonicalize()?,
+            writer,
+        })
+    }
+
+    pub fn add(&mut self, path: &Path, metadata: &Metadata) -> Fallible {
+        self.write_entry(path, metadata, None)
+    }
+
+    pub fn add_symlink(&mut self, path: &Path, metadata: &Metadata, target: &Path) -> Fallible {
+        self.write_entry(path, metadata, Some(target))
+    }
+
+    fn write_entry(&mut self, path: &Path, metadata: &Metadata, target: Option<&Path>) -> Fallible {
+        write!(
+            self.writer,
+            "{:o} {} {} ",
+            metadata.mode(),
+            metadata.mtime(),
+            metadata.mtime_nsec()
+        )?;
+        self.writer.write_all(path.as_os_str().as_bytes())?;
+
+        if let Some(target) = target {
+            self.writer.write_all(b"\0")?;
+            self.writer.write_all(target.as_os_str().as_bytes())?;
+        }
+
+        self.writer.write_all(b"\n")?;
+
+        Ok(())
+    }
+
+    pub fn add_xattrs(&mut self, archive_path: &Path, path: &Path) -> Fallible {
+        for (name, value) in read_xattrs(path)? {
+            write!(
+                self.writer,
+                "xattr {} {} ",
+                to_hex(name.as_bytes()),
+                to_hex(&value)
+            )?;
+            self.writer.write_all(archive_path.as_os_str().as_bytes())?;
+            self.writer.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Fallible<PathBuf> {
+        self.writer.flush()?;
+
+        Ok(self.path)
+    }
+}
+
+pub struct Manifest {
+    entries: HashMap<PathBuf, Entry>,
+    preserve_xattrs: bool,
+}
+
+struct Entry {
+    mode: u32,
+    mtime: SystemTime,
+    target: Option<PathBuf>,
+    xattrs: Vec<(OsString, Vec<u8>)>,
+}
+
+impl Manifest {
+    pub fn load(config: &Config, dirs: &[&Path]) -> Fallible<Option<Self>> {
+        let manifest = match &config.metadata_manifest {
+            Some(manifest) => current_dir()?.join(manifest),
+            None => {
+                warn!("Skipping restoration of metadata as no manifest is configured");
+                return Ok(None);
+            }
+        };
+
+        let manifest = match archive_path(config, &manifest) {
+            Some(manifest) => manifest,
+            None => {
+                warn!(
+                    "Skipping restoration of metadata as manifest {} is outside of relative root",
+                    manifest.display()
+                );
+                return Ok(None);
+            }
+        };
+
+        let manifest_file = match dirs
+            .iter()
+            .find_map(|dir| File::open(restored_path(dir, &manifest)).ok())
+        {
+            Some(manifest_file) => manifest_file,
+            None => {
+                warn!(
+                    "Skipping restoration of metadata as manifest {} was not restored",
+                    manifest.display(),
+                );
+                return Ok(None);
+            }
+        };
+
+        let mut entries = HashMap::<PathBuf, Entry>::new();
+
+        for line in BufReader::new(manifest_file).split(b'\n') {
+            let line = line?;
+
+            if let Some(line) = line.strip_prefix(b"xattr ") {
+                match parse_xattr_line(line) {
+                    Some((path, xattr)) => {
+                        if let Some(entry) = entries.get_mut(&path) {
+                            entry.xattrs.push(xattr);
+                        }
+                    }
+                    None => warn!("Skipping malformed extended attribute in metadata manifest"),
+                }
+
+                continue;
+            }
+
+            let (path, entry) = match parse_line(&line) {
+                Some(entry) => entry,
+                None => {
+                    warn!("Skipping malformed line in metadata manifest");
+                    continue;
+                }
+            };
+
+            entries.insert(path, entry);
+        }
+
+        Ok(Some(Self {
+            entries,
+            preserve_xattrs: config.preserve_xattrs,
+        }))
+    }
+
+    pub fn apply(&self, archive_path: &Path, path: &Path) -> Fallible<bool> {
+        let entry = match self.entries.get(archive_path) {
+            Some(entry) if entry.target.is_none() => entry,
+            _ => return Ok(false),
+        };
+
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(entry.mtime)?;
+        set_permissions(path, Permissions::from_mode(entry.mode & 0o7777))?;
+
+        if self.preserve_xattrs {
+            for (name, value) in &entry.xattrs {
+                if let Err(err) = write_xattr(path, name, value) {
+                    warn!(
+                        "Failed to restore extended attribute {} of file {}: {}",
+                        name.to_string_lossy(),
+                        path.display(),
+                        err
+                    );
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    pub fn restore_dirs<F>(&self, target_path: F) -> usize
+    where
+        F: Fn(&Path) -> Option<PathBuf>,
+    {
+        let mut restored = 0;
+
+        for (archive_path, entry) in &self.entries {
+            if entry.target.is_some() || entry.mode & libc::S_IFMT != libc::S_IFDIR {
+                continue;
+            }
+
+            let path = match target_path(archive_path) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            if path.symlink_metadata().is_ok() {
+                continue;
+            }
+
+            let res = create_dir_all(&path)
+                .and_then(|()| set_permissions(&path, Permissions::from_mode(entry.mode & 0o7777)));
+
+            match res {
+                Ok(()) => restored += 1,
+                Err(err) => warn!("Failed to restore directory {}: {}", path.display(), err),
+            }
+        }
+
+        restored
+    }
+
+    // Must run last as writing files, creating symbolic links or restoring subdirectories
+    // updates the modification time of the containing directory. Hence deeper directories
+    // are also handled before their parents.
+    pub fn restore_dir_times<F>(&self, target_path: F) -> usize
+    where
+        F: Fn(&Path) -> Option<PathBuf>,
+    {
+        let mut dirs = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry.target.is_none() && entry.mode & libc::S_IFMT == libc::S_IFDIR
+            })
+            .collect::<Vec<_>>();
+
+        dirs.sort_unstable_by_key(|(archive_path, _)| Reverse(archive_path.components().count()));
+
+        let mut restored = 0;
+
+        for (archive_path, entry) in dirs {
+            let path = match target_path(archive_path) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            if !path
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.is_dir())
+            {
+                continue;
+            }
+
+            match File::open(&path).and_then(|dir| dir.set_modified(entry.mtime)) {
+                Ok(()) => restored += 1,
+                Err(err) => warn!(
+                    "Failed to restore modification time of directory {}: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+
+        restored
+    }
+
+    pub fn restore_symlinks<F>(&self, target_path: F) -> usize
+    where
+        F: Fn(&Path) -> Option<PathBuf>,
+    {
+        let mut restored = 0;
+
+        for (archive_path, entry) in &self.entries {
+            let target = match &entry.target {
+                Some(target) => target,
+                None => continue,
+            };
+
+            let path = match target_path(archive_path) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            if path.symlink_metadata().is_ok() {
+                continue;
+            }
+
+            let res = path
+                .parent()
+                .map_or(Ok(()), create_dir_all)
+                .and_then(|()| symlink(target, &path));
+
+            match res {
+                Ok(()) => restored += 1,
+                Err(err) => warn!(
+                    "Failed to restore symbolic link {}: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+
+        restored
+    }
+}
+
+pub fn apply_manifest(config: &Config, out_dir: &Path) -> Fallible {
+    let manifest = match Manifest::load(config, &[out_dir])? {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+
+    let mut restored = 0;
+
+    for archive_path in manifest.entries.keys() {
+        let path = restored_path(out_dir, archive_path);
+
+        if !path
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_file())
+        {
+            continue;
+        }
+
+        match manifest.apply(archive_path, &path) {
+            Ok(true) => restored += 1,
+            Ok(false) => (),
+            Err(err) => warn!(
+                "Failed to restore metadata of file {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+
+    let dirs = manifest.restore_dirs(|path| Some(restored_path(out_dir, path)));
+    let symlinks = manifest.restore_symlinks(|path| Some(restored_path(out_dir, path)));
+    manifest.restore_dir_times(|path| Some(restored_path(out_dir, path)));
+
+    info!(
+        "Restored metadata of {restored} files, {dirs} directories and {symlinks} symbolic links."
+    );
+
+    Ok(())
+}
+
+fn parse_line(line: &[u8]) -> Option<(PathBuf, Entry)> {
+    let mut fields = line.splitn(4, |byte| *byte == b' ');
+
+    let mode = u32::from_str_radix(from_utf8(fields.next()?).ok()?, 8).ok()?;
+    let secs = from_utf8(fields.next()?).ok()?.parse::<u64>().ok()?;
+    let nsecs = from_utf8(fields.next()?).ok()?.parse::<u32>().ok()?;
+
+    let mut path_and_target = fields.next()?.splitn(2, |byte| *byte == b'\0');
+    let path = PathBuf::from(OsStr::from_bytes(path_and_target.next()?));
+    let target = path_and_target
+        .next()
+        .map(|target| PathBuf::from(OsStr::from_bytes(target)));
+
+    let mtime = UNIX_EPOCH.checked_add(Duration::new(secs, nsecs))?;
+
+    Some((
+        path,
+        Entry {
+            mode,
+            mtime,
+            target,
+            xattrs: Vec::new(),
+        },
+    ))
+}
+
+fn parse_xattr_line(line: &[u8]) -> Option<(PathBuf, (OsString, Vec<u8>))> {
+    let mut fields = line.splitn(3, |byte| *byte == b' ');
+
+    let name = OsString::from_vec(from_hex(fields.next()?)?);
+    let value = from_hex(fields.next()?)?;
+    let path = PathBuf::from(OsStr::from_bytes(fields.next()?));
+
+    Some((path, (name, value)))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+pub fn restored_path(out_dir: &Path, path: &Path) -> PathBuf {
+    out_dir.join(path.strip_prefix("/").unwrap_or(path))
+}
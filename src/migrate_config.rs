@@ -0,0 +1,62 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::ffi::OsString;
+use std::fs::{copy, write};
+use std::path::{Path, PathBuf};
+
+use serde_yaml::{from_value as from_yaml_value, to_string as to_yaml_string};
+
+use super::{read_config_value, unknown_keys, Config, Fallible};
+
+pub fn migrate_config(config_path: &Path) -> Fallible {
+    let value = read_config_value(config_path)?;
+    let config = from_yaml_value::<Config>(value.clone())?;
+
+    for key in unknown_keys(&value, &config)? {
+        warn!("Dropping unknown config key {key}");
+    }
+
+    let backup_file = backup_path(config_path);
+    copy(config_path, &backup_file)?;
+
+    let mut migrated = format!(
+        "# Migrated by {} {}, previous version saved as {}.\n# Keys set to null are optional and unused.\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        backup_file.display()
+    );
+    migrated.push_str(&to_yaml_string(&config)?);
+
+    write(config_path, migrated)?;
+
+    info!(
+        "Migrated {} and saved previous version as {}",
+        config_path.display(),
+        backup_file.display()
+    );
+
+    Ok(())
+}
+
+pub fn backup_path(config_path: &Path) -> PathBuf {
+    let mut backup_path = OsString::from(config_path);
+    backup_path.push(".bak");
+
+    backup_path.into()
+}
@@ -0,0 +1,165 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{read, write, Metadata};
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::from_utf8;
+
+use super::Fallible;
+
+pub const MTIME_CACHE_FILE: &str = "mtime_cache";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl Entry {
+    fn new(metadata: &Metadata) -> Self {
+        Self {
+            size: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        }
+    }
+}
+
+// Remembers size and modification time of every file seen during the last successful backup,
+// so that files which still match are not submitted to idevsutil_dedup at all.
+pub struct MtimeCache {
+    previous: HashMap<PathBuf, Entry>,
+    current: HashMap<PathBuf, Entry>,
+}
+
+impl MtimeCache {
+    // Entries from the future are dropped as the clock must have been skewed when they were recorded.
+    pub fn load(now: i64) -> Fallible<Self> {
+        let mut previous = match read(MTIME_CACHE_FILE) {
+            Ok(buf) => parse_cache(&buf),
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        previous.retain(|_, entry| entry.mtime <= now);
+
+        Ok(Self {
+            previous,
+            current: HashMap::new(),
+        })
+    }
+
+    pub fn unchanged(&mut self, path: &Path, metadata: &Metadata) -> bool {
+        let entry = Entry::new(metadata);
+
+        self.current.insert(path.to_path_buf(), entry);
+
+        self.previous.get(path) == Some(&entry)
+    }
+
+    pub fn forget(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            self.previous.remove(path);
+            self.current.remove(path);
+        }
+    }
+
+    // A partial backup merges its entries instead of replacing those of the files it did not visit.
+    pub fn save(mut self, partial: bool) -> Fallible {
+        if partial {
+            self.previous.extend(self.current);
+        } else {
+            self.previous = self.current;
+        }
+
+        let mut buf = Vec::new();
+
+        for (path, entry) in &self.previous {
+            buf.extend_from_slice(
+                format!("{} {} {} ", entry.size, entry.mtime, entry.mtime_nsec).as_bytes(),
+            );
+            buf.extend_from_slice(path.as_os_str().as_bytes());
+            buf.push(b'\n');
+        }
+
+        write(MTIME_CACHE_FILE, buf)?;
+
+        Ok(())
+    }
+}
+
+fn parse_cache(buf: &[u8]) -> HashMap<PathBuf, Entry> {
+    let mut entries = HashMap::new();
+
+    for line in buf.split(|byte| *byte == b'\n') {
+        let mut fields = line.splitn(4, |byte| *byte == b' ');
+
+        let mut number = || fields.next().and_then(|field| from_utf8(field).ok());
+
+        let size = number().and_then(|size| size.parse().ok());
+        let mtime = number().and_then(|mtime| mtime.parse().ok());
+        let mtime_nsec = number().and_then(|mtime_nsec| mtime_nsec.parse().ok());
+
+        if let (Some(size), Some(mtime), Some(mtime_nsec), Some(path)) =
+            (size, mtime, mtime_nsec, fields.next())
+        {
+            entries.insert(
+                PathBuf::from(OsStr::from_bytes(path)),
+                Entry {
+                    size,
+                    mtime,
+                    mtime_nsec,
+                },
+            );
+        }
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn forget_drops_failed_files() {
+        let file = NamedTempFile::new().unwrap();
+        let metadata = file.path().metadata().unwrap();
+
+        let mut cache = MtimeCache {
+            previous: HashMap::from([(file.path().to_path_buf(), Entry::new(&metadata))]),
+            current: HashMap::new(),
+        };
+
+        assert!(cache.unchanged(file.path(), &metadata));
+
+        cache.forget(&[file.path().to_path_buf()]);
+
+        assert!(cache.previous.is_empty());
+        assert!(cache.current.is_empty());
+        assert!(!cache.unchanged(file.path(), &metadata));
+    }
+}
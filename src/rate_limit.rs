@@ -0,0 +1,94 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::ffi::OsString;
+use std::sync::Mutex;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Halves the bandwidth limit of transfers after consecutive failures
+// and doubles it again after each success, up to the configured maximum.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct AdaptiveRateLimit {
+    // In KiB/s as expected by --bwlimit
+    max_rate: u64,
+    #[serde(default = "default_min_rate")]
+    min_rate: u64,
+    #[serde(default = "default_failures_before_backoff")]
+    failures_before_backoff: usize,
+    #[serde(skip)]
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    rate: Option<u64>,
+    failures: usize,
+}
+
+impl AdaptiveRateLimit {
+    pub fn arg(&self) -> OsString {
+        let state = self.state.lock().unwrap();
+
+        format!("--bwlimit={}", state.rate.unwrap_or(self.max_rate)).into()
+    }
+
+    pub fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        let rate = state.rate.unwrap_or(self.max_rate);
+
+        if success {
+            state.failures = 0;
+
+            let new_rate = rate.saturating_mul(2).min(self.max_rate);
+
+            if new_rate != rate {
+                info!("Increasing transfer rate limit to {new_rate} KiB/s after success");
+            }
+
+            state.rate = Some(new_rate);
+        } else {
+            state.failures += 1;
+
+            if state.failures < self.failures_before_backoff {
+                return;
+            }
+
+            let new_rate = (rate / 2).max(self.min_rate.min(self.max_rate));
+
+            if new_rate != rate {
+                warn!(
+                    "Reducing transfer rate limit to {} KiB/s after {} consecutive failures",
+                    new_rate, state.failures
+                );
+            }
+
+            state.failures = 0;
+            state.rate = Some(new_rate);
+        }
+    }
+}
+
+fn default_min_rate() -> u64 {
+    64
+}
+
+fn default_failures_before_backoff() -> usize {
+    2
+}
@@ -16,15 +16,73 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
 */
-use std::ffi::{OsStr, OsString};
-use std::io::{BufWriter, Write};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
 
-use serde::Deserialize;
-use tempfile::NamedTempFile;
+use serde::{Serialize, Serializer};
+use serde_json::to_string as to_json_string;
 
-use super::{format_size, list_dir, make_arg, parse_items, run_util, Config, Fallible};
+use super::backup::parse_percentage;
+use super::glob::glob_match;
+use super::idevsutil::Transfer;
+use super::metadata::apply_manifest;
+use super::restore_missing::{restore_items, transfer_items};
+use super::staging::{create_parent_dirs, create_staging_dir, needs_staging, promote_staged_files};
+use super::{confirm, context, format_size, list_dir, list_remote_dir, walk_dir, Config, Fallible};
+
+#[derive(Default)]
+pub struct Options {
+    pub restore_metadata: bool,
+    pub atomic: bool,
+    pub force: bool,
+    pub strip_components: usize,
+    pub path_map: Vec<(PathBuf, PathBuf)>,
+    pub no_verify_size: bool,
+    pub manifest: Option<PathBuf>,
+    pub jobs: usize,
+    // Running total of bytes transferred by all batches and jobs for progress reporting
+    pub transferred: AtomicU64,
+}
+
+impl Options {
+    pub fn rewrites_paths(&self) -> bool {
+        self.strip_components != 0 || !self.path_map.is_empty()
+    }
+
+    // The first matching path mapping is applied before leading components are stripped,
+    // yielding nothing if no components remain.
+    pub fn target_path(&self, out_dir: &Path, archive_path: &Path) -> Option<PathBuf> {
+        let mut path = archive_path.strip_prefix("/").unwrap_or(archive_path);
+
+        let mapped_path = self
+            .path_map
+            .iter()
+            .find_map(|(from, to)| Some(to.join(path.strip_prefix(from).ok()?)));
+
+        if let Some(mapped_path) = &mapped_path {
+            path = mapped_path;
+        }
+
+        let mut components = path.components();
+
+        for _ in 0..self.strip_components {
+            components.next()?;
+        }
+
+        let path = components.as_path();
+
+        if path.as_os_str().is_empty() {
+            return None;
+        }
+
+        Some(out_dir.join(path))
+    }
+}
 
 pub fn restore(
     config: &Config,
@@ -32,52 +90,628 @@ pub fn restore(
     dev_id: &str,
     sub_dir: &Path,
     out_dir: &Path,
+    opts: &Options,
 ) -> Fallible {
-    eprintln!(
+    info!(
         "Restoring backup of {} ({}) from {}...",
         config.device_name, dev_id, srv_ip
     );
 
-    let list_file = NamedTempFile::new()?;
+    check_overwrites(config, srv_ip, dev_id, sub_dir, out_dir, opts)?;
 
-    {
-        let mut list_file = BufWriter::new(list_file.as_file());
+    let mut items = Vec::new();
+    let mut remote_dirs = HashSet::new();
+    remote_dirs.insert(Path::new("/").join(sub_dir));
 
-        for (entry, _) in list_dir(config, srv_ip, dev_id, sub_dir)? {
-            list_file.write_all(entry.as_os_str().as_bytes())?;
-            list_file.write_all(b"\n")?;
+    // Entries are listed relative to the sub-directory, but transferred relative to the archive root.
+    for (entry, is_dir) in list_dir(config, srv_ip, dev_id, sub_dir)? {
+        let item = Path::new("/").join(sub_dir).join(&entry);
+
+        if is_dir {
+            remote_dirs.insert(item.clone());
         }
+
+        items.push(item);
     }
 
-    let output = run_util(
+    let staging_dir = if needs_staging(config, opts) {
+        Some(create_staging_dir(out_dir).map_err(context("Failed to create staging directory"))?)
+    } else {
+        None
+    };
+
+    create_parent_dirs(
+        staging_dir
+            .as_ref()
+            .map_or(out_dir, |staging_dir| staging_dir.path()),
+        &items,
+        &remote_dirs,
+    )
+    .map_err(context("Failed to create parent directories"))?;
+
+    let (total_transfer_size, mut restored) = transfer_items(
         config,
-        [
-            OsStr::new("--xml-output"),
-            &make_arg("--files-from=", list_file.path()),
-            &make_arg("--device-id=", dev_id),
-            &OsString::from(format!("{}@{}::home/", config.username, srv_ip)),
-            out_dir.as_os_str(),
-        ],
+        srv_ip,
+        dev_id,
+        staging_dir
+            .as_ref()
+            .map_or(out_dir, |staging_dir| staging_dir.path()),
+        opts,
+        &items,
     )?;
 
-    #[derive(Deserialize)]
-    #[serde(rename = "item")]
-    struct Transfer {
-        #[serde(rename = "tottrf_sz")]
-        total_size: u64,
+    let (size, unit) = format_size(total_transfer_size, config.size_units);
+
+    info!("Transferred {size:.1} {unit} during restore.");
+
+    restored.add_transferred(total_transfer_size);
+
+    if let Some(staging_dir) = staging_dir {
+        promote_staged_files(config, staging_dir.path(), out_dir, opts)
+            .map_err(context("Failed to move restored files into place"))?;
+    } else if opts.restore_metadata {
+        apply_manifest(config, out_dir).map_err(context("Failed to restore metadata"))?;
     }
 
-    let transfers = parse_items::<Transfer>(output)?;
+    if verifies_sizes(config, opts) {
+        let mut sizes = Vec::new();
+
+        walk_dir(config, srv_ip, dev_id, sub_dir, |path, size, _| {
+            if let Some(size) = size {
+                sizes.push((path.clone(), size));
+            }
+
+            Ok(Some(path))
+        })
+        .map_err(context("Failed to list restored files"))?;
+
+        verify_sizes(out_dir, opts, &sizes);
+    }
 
-    let total_transfer_size = transfers
+    restored
+        .write_manifest(config, out_dir, opts)
+        .map_err(context("Failed to write restore manifest"))?;
+
+    restored.print_json(config)
+}
+
+pub fn restore_files(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    files: &[PathBuf],
+    out_dir: &Path,
+    opts: &Options,
+) -> Fallible {
+    let files = files
         .iter()
-        .map(|transfer| transfer.total_size)
-        .max()
-        .unwrap_or(0);
+        .map(|file| Path::new("/").join(file))
+        .collect::<BTreeSet<_>>();
+
+    let mut parents = BTreeMap::<&Path, Vec<&OsStr>>::new();
+
+    for file in &files {
+        match (file.parent(), file.file_name()) {
+            (Some(parent), Some(name)) => parents.entry(parent).or_default().push(name),
+            _ => return Err(format!("{} does not name a file", file.display()).into()),
+        }
+    }
+
+    info!(
+        "Restoring {} files from backup of {} ({}) from {}...",
+        files.len(),
+        config.device_name,
+        dev_id,
+        srv_ip
+    );
+
+    // Each parent directory is listed only once, no matter how many of its files are requested.
+    let mut sizes = Vec::new();
+    let mut missing = Vec::new();
+
+    for (parent, names) in &parents {
+        let entries = list_remote_dir(config, srv_ip, dev_id, parent)
+            .map_err(context("Failed to list parent directory"))?;
+
+        for name in names {
+            let file = parent.join(name);
+
+            match entries.iter().find(|entry| entry.name.as_os_str() == *name) {
+                Some(entry) if entry.is_dir => {
+                    return Err(format!(
+                        "{} is a directory in the archive, use --sub-dir to restore it",
+                        file.display()
+                    )
+                    .into())
+                }
+                Some(entry) => {
+                    if let Some(size) = entry.size {
+                        sizes.push((file, size));
+                    }
+                }
+                None => missing.push(file),
+            }
+        }
+    }
+
+    if !missing.is_empty() && !config.print_args {
+        return Err(format!(
+            "Files {} do not exist in the archive of {}",
+            missing
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            config.device_name
+        )
+        .into());
+    }
+
+    if let Some(restore_max_overwrite) = config.restore_max_overwrite.filter(|_| !opts.force) {
+        let mut overwrites = 0;
+
+        for file in &files {
+            let target = opts
+                .target_path(out_dir, file)
+                .ok_or("No path components remain after stripping")?;
+
+            if target.exists() {
+                overwrites += 1;
+            }
+        }
+
+        if overwrites > restore_max_overwrite
+            && !confirm(
+                config,
+                &format!(
+                    "Restoring would overwrite {overwrites} existing files which exceeds the limit of {restore_max_overwrite}, proceed?"
+                ),
+            )?
+        {
+            return Err(format!(
+                "Restoring would overwrite {overwrites} existing files which exceeds the limit of {restore_max_overwrite}, use --force or --assume-yes to proceed"
+            )
+            .into());
+        }
+    }
+
+    let remote_dirs = parents
+        .keys()
+        .map(|parent| parent.to_path_buf())
+        .collect::<HashSet<_>>();
+
+    let files = files.into_iter().collect::<Vec<_>>();
+
+    let restored = restore_items(config, srv_ip, dev_id, out_dir, opts, &files, &remote_dirs)?;
+
+    if opts.restore_metadata && !needs_staging(config, opts) {
+        apply_manifest(config, out_dir).map_err(context("Failed to restore metadata"))?;
+    }
+
+    if verifies_sizes(config, opts) {
+        verify_sizes(out_dir, opts, &sizes);
+    }
+
+    restored
+        .write_manifest(config, out_dir, opts)
+        .map_err(context("Failed to write restore manifest"))?;
+
+    restored.print_json(config)
+}
+
+// Files which idevsutil reported as completely transferred, identified by their archive paths,
+// together with the number of bytes transferred for them.
+#[derive(Default, Serialize)]
+pub struct Restored {
+    #[serde(rename = "restored_files", serialize_with = "serialize_len")]
+    files: BTreeSet<PathBuf>,
+    transferred_bytes: u64,
+}
+
+fn serialize_len<S: Serializer>(
+    files: &BTreeSet<PathBuf>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(files.len() as u64)
+}
+
+impl Restored {
+    // Yields whether the transfer completed a file which was not yet recorded.
+    pub fn record(&mut self, transfer: &Transfer) -> bool {
+        if transfer.file_name.is_empty()
+            || !parse_percentage(&transfer.percentage).is_some_and(|percentage| percentage >= 100.0)
+        {
+            return false;
+        }
+
+        self.files.insert(Path::new("/").join(&transfer.file_name))
+    }
+
+    pub fn add_transferred(&mut self, bytes: u64) {
+        self.transferred_bytes += bytes;
+    }
+
+    pub fn extend(&mut self, other: Self) {
+        self.files.extend(other.files);
+        self.transferred_bytes += other.transferred_bytes;
+    }
+
+    pub fn print_json(&self, config: &Config) -> Fallible {
+        if config.json && !config.print_args {
+            println!("{}", to_json_string(self)?);
+        }
+
+        Ok(())
+    }
+
+    // Lists the local path and size of each restored file, one per line and separated by a tab,
+    // using `-` for files which are no longer present locally.
+    pub fn write_manifest(&self, config: &Config, out_dir: &Path, opts: &Options) -> Fallible {
+        let manifest = match &opts.manifest {
+            Some(manifest) if !config.print_args => manifest,
+            _ => return Ok(()),
+        };
+
+        let mut writer: BufWriter<Box<dyn Write>> = if manifest.as_os_str() == "-" {
+            BufWriter::new(Box::new(stdout().lock()))
+        } else {
+            BufWriter::new(Box::new(File::create(manifest)?))
+        };
+
+        for archive_path in &self.files {
+            let target = match opts.target_path(out_dir, archive_path) {
+                Some(target) => target,
+                None => continue,
+            };
+
+            match target.metadata() {
+                Ok(metadata) => write!(writer, "{}\t", metadata.len())?,
+                Err(_) => write!(writer, "-\t")?,
+            }
+
+            writer.write_all(target.as_os_str().as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+
+        writer.flush()?;
+
+        if manifest.as_os_str() != "-" {
+            info!(
+                "Recorded {} restored files in manifest {}.",
+                self.files.len(),
+                manifest.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+// Client-side encryption changes the size of files, hence they cannot be compared.
+pub fn verifies_sizes(config: &Config, opts: &Options) -> bool {
+    !opts.no_verify_size && !config.print_args && config.client_encryption_key.is_none()
+}
 
-    let (size, unit) = format_size(total_transfer_size);
+// Only catches truncated or otherwise incomplete files, but does not require hashing their contents.
+pub fn verify_sizes(out_dir: &Path, opts: &Options, sizes: &[(PathBuf, u64)]) {
+    let mut mismatches = 0;
 
-    eprintln!("Transferred {size:.1} {unit} during restore.");
+    for (archive_path, size) in sizes {
+        let target = match opts.target_path(out_dir, archive_path) {
+            Some(target) => target,
+            None => continue,
+        };
+
+        match target.metadata() {
+            Ok(metadata) if metadata.len() == *size => (),
+            Ok(metadata) => {
+                warn!(
+                    "WARNING: Restored file {} has {} bytes instead of {} bytes",
+                    target.display(),
+                    metadata.len(),
+                    size
+                );
+
+                mismatches += 1;
+            }
+            Err(err) => {
+                warn!(
+                    "WARNING: Restored file {} could not be checked: {}",
+                    target.display(),
+                    err
+                );
+
+                mismatches += 1;
+            }
+        }
+    }
+
+    if mismatches != 0 {
+        warn!(
+            "WARNING: {} out of {} restored files do not match their size in the archive!",
+            mismatches,
+            sizes.len()
+        );
+    } else {
+        info!("Verified sizes of {} restored files.", sizes.len());
+    }
+}
+
+fn check_overwrites(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    sub_dir: &Path,
+    out_dir: &Path,
+    opts: &Options,
+) -> Fallible {
+    let restore_max_overwrite = match config.restore_max_overwrite {
+        Some(restore_max_overwrite) if !opts.force => restore_max_overwrite,
+        _ => return Ok(()),
+    };
+
+    let mut overwrites = 0;
+
+    walk_dir(config, srv_ip, dev_id, sub_dir, |path, size, _| {
+        if size.is_some()
+            && opts
+                .target_path(out_dir, &path)
+                .is_some_and(|target| target.exists())
+        {
+            overwrites += 1;
+        }
+
+        Ok(Some(path))
+    })
+    .map_err(context("Failed to count files to be overwritten"))?;
+
+    if overwrites > restore_max_overwrite
+        && !confirm(
+            config,
+            &format!(
+                "Restoring would overwrite {overwrites} existing files which exceeds the limit of {restore_max_overwrite}, proceed?"
+            ),
+        )?
+    {
+        return Err(format!(
+            "Restoring would overwrite {overwrites} existing files which exceeds the limit of {restore_max_overwrite}, use --force or --assume-yes to proceed"
+        )
+        .into());
+    }
 
     Ok(())
 }
+
+pub fn restore_glob(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    sub_dir: &Path,
+    pattern: &str,
+    out_dir: &Path,
+    opts: &Options,
+) -> Fallible {
+    info!(
+        "Restoring files matching {} from backup of {} ({}) from {}...",
+        pattern, config.device_name, dev_id, srv_ip
+    );
+
+    let root = Path::new("/").join(sub_dir);
+
+    let mut items = Vec::new();
+    let mut sizes = Vec::new();
+    let mut remote_dirs = HashSet::new();
+    let mut restored = Restored::default();
+    remote_dirs.insert(root.clone());
+
+    walk_dir(config, srv_ip, dev_id, sub_dir, |path, size, _| {
+        if size.is_none() {
+            remote_dirs.insert(path.clone());
+        } else if glob_match(pattern, path.strip_prefix(&root).unwrap_or(&path)) {
+            debug!("Restoring item {} from archive", path.display());
+
+            items.push(path.clone());
+            sizes.push((path.clone(), size.unwrap_or(0)));
+
+            if items.len() == config.restore_batch_size.max(1) * opts.jobs.max(1) {
+                restored.extend(
+                    restore_items(config, srv_ip, dev_id, out_dir, opts, &items, &remote_dirs)
+                        .map_err(context("Failed to restore items"))?,
+                );
+
+                items.clear();
+            }
+        }
+
+        Ok(Some(path))
+    })?;
+
+    if !items.is_empty() {
+        restored.extend(
+            restore_items(config, srv_ip, dev_id, out_dir, opts, &items, &remote_dirs)
+                .map_err(context("Failed to restore items"))?,
+        );
+    }
+
+    if opts.restore_metadata && !needs_staging(config, opts) {
+        apply_manifest(config, out_dir).map_err(context("Failed to restore metadata"))?;
+    }
+
+    if verifies_sizes(config, opts) {
+        verify_sizes(out_dir, opts, &sizes);
+    }
+
+    restored
+        .write_manifest(config, out_dir, opts)
+        .map_err(context("Failed to write restore manifest"))?;
+
+    restored.print_json(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, read_to_string, write};
+
+    use tempfile::TempDir;
+
+    use super::super::test_util::{config, FakeUtil, Invocation};
+
+    // Answers listings using the given entries per directory and transfers by creating the listed
+    // files below the target directory, which is always passed last.
+    fn fake_archive(invocation: &Invocation, dirs: &[(&str, &str)]) -> (String, i32) {
+        if invocation.has_arg("--auth-list") {
+            let output = dirs
+                .iter()
+                .find(|(dir, _)| invocation.server_path() == format!("user@1.2.3.4::home/{dir}"))
+                .map_or("", |(_, entries)| entries);
+
+            return (output.to_owned(), 0);
+        }
+
+        let target_dir = Path::new(invocation.args.last().unwrap());
+        let mut output = String::new();
+
+        for (idx, file) in invocation.files_from.iter().enumerate() {
+            let target = target_dir.join(file.trim_start_matches('/'));
+            create_dir_all(target.parent().unwrap()).unwrap();
+            write(&target, "0123456789").unwrap();
+
+            output.push_str(&format!(
+                "<item per=\"100%\" fname=\"{}\" trf_type=\"FULL\" rate_trf=\"1.00MB/s\" tottrf_sz=\"{}\"/>\n",
+                file.trim_start_matches('/'),
+                10 * (idx + 1)
+            ));
+        }
+
+        (output, 0)
+    }
+
+    #[test]
+    fn restore_prefixes_items_with_sub_dir() {
+        let out_dir = TempDir::new().unwrap();
+
+        let mut config = config("");
+        let fake = FakeUtil::install(&mut config, |invocation| {
+            fake_archive(
+                invocation,
+                &[(
+                    "home/me/documents",
+                    r#"<item restype="F" fname="notes.txt" size="10"/>
+<item restype="D" fname="foo"/>"#,
+                )],
+            )
+        });
+
+        restore(
+            &config,
+            "1.2.3.4",
+            "5c0b123",
+            Path::new("/home/me/documents"),
+            out_dir.path(),
+            &Options::default(),
+        )
+        .unwrap();
+
+        let invocations = fake.invocations();
+        assert_eq!(
+            invocations[0].server_path(),
+            "user@1.2.3.4::home/home/me/documents"
+        );
+
+        let transfer = invocations
+            .iter()
+            .find(|invocation| !invocation.has_arg("--auth-list"))
+            .unwrap();
+        assert_eq!(
+            transfer.files_from,
+            ["/home/me/documents/notes.txt", "/home/me/documents/foo"]
+        );
+
+        assert!(out_dir.path().join("home/me/documents/notes.txt").is_file());
+        assert!(out_dir.path().join("home/me/documents/foo").exists());
+        assert!(!out_dir.path().join("notes.txt").exists());
+        assert!(!out_dir.path().join("foo").exists());
+    }
+
+    #[test]
+    fn restore_files_transfers_requested_files() {
+        let out_dir = TempDir::new().unwrap();
+        let manifest = out_dir.path().join("manifest");
+
+        let mut config = config("");
+        let fake = FakeUtil::install(&mut config, |invocation| {
+            fake_archive(
+                invocation,
+                &[(
+                    "home/me",
+                    r#"<item restype="F" fname="notes.txt" size="10"/>
+<item restype="F" fname="todo.txt" size="10"/>
+<item restype="D" fname="docs"/>"#,
+                )],
+            )
+        });
+
+        let opts = Options {
+            manifest: Some(manifest.clone()),
+            ..Default::default()
+        };
+
+        restore_files(
+            &config,
+            "1.2.3.4",
+            "5c0b123",
+            &[
+                PathBuf::from("home/me/notes.txt"),
+                PathBuf::from("/home/me/todo.txt"),
+            ],
+            out_dir.path(),
+            &opts,
+        )
+        .unwrap();
+
+        let invocations = fake.invocations();
+        assert_eq!(invocations.len(), 2);
+        assert_eq!(invocations[0].server_path(), "user@1.2.3.4::home/home/me");
+        assert_eq!(
+            invocations[1].files_from,
+            ["/home/me/notes.txt", "/home/me/todo.txt"]
+        );
+
+        assert_eq!(
+            read_to_string(&manifest).unwrap(),
+            format!(
+                "10\t{0}/home/me/notes.txt\n10\t{0}/home/me/todo.txt\n",
+                out_dir.path().display()
+            )
+        );
+        assert_eq!(opts.transferred.into_inner(), 20);
+    }
+
+    #[test]
+    fn restore_files_rejects_missing_files() {
+        let out_dir = TempDir::new().unwrap();
+
+        let mut config = config("");
+        let fake = FakeUtil::install(&mut config, |invocation| {
+            fake_archive(
+                invocation,
+                &[("home/me", r#"<item restype="F" fname="notes.txt"/>"#)],
+            )
+        });
+
+        let err = restore_files(
+            &config,
+            "1.2.3.4",
+            "5c0b123",
+            &[PathBuf::from("/home/me/missing.txt")],
+            out_dir.path(),
+            &Options::default(),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("do not exist in the archive"));
+        assert_eq!(fake.invocations().len(), 1);
+    }
+}
@@ -16,15 +16,23 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
 */
-use std::ffi::{OsStr, OsString};
+use std::collections::HashSet;
+use std::ffi::OsStr;
 use std::io::{BufWriter, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::scope;
 
-use serde::Deserialize;
-use tempfile::NamedTempFile;
-
-use super::{context, format_size, make_arg, parse_items, run_util, walk_dir, Config, Fallible};
+use super::idevsutil::Transfer;
+use super::interrupt::interrupted;
+use super::metadata::apply_manifest;
+use super::restore::{verifies_sizes, verify_sizes, Options, Restored};
+use super::staging::{create_parent_dirs, create_staging_dir, needs_staging, promote_staged_files};
+use super::{
+    context, format_size, local_path, make_arg, new_temp_file, remote_home, retry_transient,
+    run_restore_streaming, walk_dir, Config, Fallible,
+};
 
 pub fn restore_missing(
     config: &Config,
@@ -32,23 +40,38 @@ pub fn restore_missing(
     dev_id: &str,
     sub_dir: &Path,
     out_dir: &Path,
+    opts: &Options,
 ) -> Fallible {
-    eprintln!(
+    info!(
         "Restoring missing files from backup of {} ({}) from {}...",
         config.device_name, dev_id, srv_ip
     );
 
     let mut items = Vec::new();
+    let mut sizes = Vec::new();
+    let mut remote_dirs = HashSet::new();
+    let mut restored = Restored::default();
+    remote_dirs.insert(Path::new("/").join(sub_dir));
+
+    walk_dir(config, srv_ip, dev_id, sub_dir, |path, size, _| {
+        if size.is_none() {
+            remote_dirs.insert(path.clone());
+        }
 
-    walk_dir(config, srv_ip, dev_id, sub_dir, |path| {
-        if path.canonicalize().is_err() {
-            eprintln!("Restoring item {} from archive", path.display());
+        if local_path(config, &path).canonicalize().is_err() {
+            debug!("Restoring item {} from archive", path.display());
 
             items.push(path.clone());
 
-            if items.len() == 100 {
-                restore_items(config, srv_ip, dev_id, out_dir, &items)
-                    .map_err(context("Failed to delete items"))?;
+            if let Some(size) = size {
+                sizes.push((path.clone(), size));
+            }
+
+            if items.len() == config.restore_batch_size.max(1) * opts.jobs.max(1) {
+                restored.extend(
+                    restore_items(config, srv_ip, dev_id, out_dir, opts, &items, &remote_dirs)
+                        .map_err(context("Failed to delete items"))?,
+                );
 
                 items.clear();
             }
@@ -57,22 +80,142 @@ pub fn restore_missing(
         Ok(Some(path))
     })?;
 
-    if !items.is_empty() {
-        restore_items(config, srv_ip, dev_id, out_dir, &items)
-            .map_err(context("Failed to delete items"))?;
+    if !items.is_empty() && !interrupted() {
+        restored.extend(
+            restore_items(config, srv_ip, dev_id, out_dir, opts, &items, &remote_dirs)
+                .map_err(context("Failed to delete items"))?,
+        );
+    }
+
+    if opts.restore_metadata && !needs_staging(config, opts) {
+        apply_manifest(config, out_dir).map_err(context("Failed to restore metadata"))?;
+    }
+
+    if verifies_sizes(config, opts) {
+        verify_sizes(out_dir, opts, &sizes);
     }
 
-    Ok(())
+    restored
+        .write_manifest(config, out_dir, opts)
+        .map_err(context("Failed to write restore manifest"))?;
+
+    restored.print_json(config)
 }
 
-fn restore_items(
+pub fn restore_items(
     config: &Config,
     srv_ip: &str,
     dev_id: &str,
-    dir: &Path,
+    out_dir: &Path,
+    opts: &Options,
     items: &[PathBuf],
-) -> Fallible {
-    let list_file = NamedTempFile::new()?;
+    remote_dirs: &HashSet<PathBuf>,
+) -> Fallible<Restored> {
+    let staging_dir = if needs_staging(config, opts) {
+        Some(create_staging_dir(out_dir).map_err(context("Failed to create staging directory"))?)
+    } else {
+        None
+    };
+
+    create_parent_dirs(
+        staging_dir
+            .as_ref()
+            .map_or(out_dir, |staging_dir| staging_dir.path()),
+        items,
+        remote_dirs,
+    )
+    .map_err(context("Failed to create parent directories"))?;
+
+    let (total_transfer_size, mut restored) = transfer_items(
+        config,
+        srv_ip,
+        dev_id,
+        staging_dir
+            .as_ref()
+            .map_or(out_dir, |staging_dir| staging_dir.path()),
+        opts,
+        items,
+    )?;
+
+    let (size, unit) = format_size(total_transfer_size, config.size_units);
+
+    info!("Transferred {size:.1} {unit} during restore.");
+
+    restored.add_transferred(total_transfer_size);
+
+    if let Some(staging_dir) = staging_dir {
+        promote_staged_files(config, staging_dir.path(), out_dir, opts)
+            .map_err(context("Failed to move restored files into place"))?;
+    }
+
+    Ok(restored)
+}
+
+// Splits the items into one chunk per job which are restored concurrently into the given directory.
+// The chunks are disjoint and parent directories must have been created beforehand, so that the
+// invocations of idevsutil do not race each other.
+pub fn transfer_items(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    target_dir: &Path,
+    opts: &Options,
+    items: &[PathBuf],
+) -> Fallible<(u64, Restored)> {
+    let chunk_size = items.len().div_ceil(opts.jobs.max(1)).max(1);
+
+    let results = scope(|scope| {
+        let handles = items
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    retry_transient(
+                        config,
+                        &format!("transfer of {} items", chunk.len()),
+                        || {
+                            transfer_chunk(
+                                config,
+                                srv_ip,
+                                dev_id,
+                                target_dir,
+                                &opts.transferred,
+                                chunk,
+                            )
+                        },
+                    )
+                    .map_err(|err| err.to_string())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let mut total_transfer_size = 0;
+    let mut restored = Restored::default();
+
+    for result in results {
+        let (transfer_size, chunk) = result?;
+
+        total_transfer_size += transfer_size;
+        restored.extend(chunk);
+    }
+
+    Ok((total_transfer_size, restored))
+}
+
+fn transfer_chunk(
+    config: &Config,
+    srv_ip: &str,
+    dev_id: &str,
+    target_dir: &Path,
+    transferred: &AtomicU64,
+    items: &[PathBuf],
+) -> Fallible<(u64, Restored)> {
+    let list_file = new_temp_file()?;
 
     {
         let mut list_file = BufWriter::new(list_file.as_file());
@@ -83,35 +226,40 @@ fn restore_items(
         }
     }
 
-    let output = run_util(
+    let mut total_transfer_size = 0;
+    let mut completed_transfer_size = 0;
+    let mut restored = Restored::default();
+
+    run_restore_streaming(
         config,
         [
             OsStr::new("--xml-output"),
             &make_arg("--files-from=", list_file.path()),
             &make_arg("--device-id=", dev_id),
-            &OsString::from(format!("{}@{}::home/", config.username, srv_ip)),
-            dir.as_os_str(),
+            &remote_home(config, srv_ip),
+            target_dir.as_os_str(),
         ],
-    )?;
+        |transfer: Transfer| {
+            total_transfer_size = total_transfer_size.max(transfer.total_size);
 
-    #[derive(Deserialize)]
-    #[serde(rename = "item")]
-    struct Transfer {
-        #[serde(rename = "tottrf_sz")]
-        total_size: u64,
-    }
+            if restored.record(&transfer) {
+                let transfer_size = total_transfer_size - completed_transfer_size;
+                completed_transfer_size = total_transfer_size;
 
-    let transfers = parse_items::<Transfer>(output)?;
+                let total = transferred.fetch_add(transfer_size, Ordering::Relaxed) + transfer_size;
 
-    let total_transfer_size = transfers
-        .iter()
-        .map(|transfer| transfer.total_size)
-        .max()
-        .unwrap_or(0);
+                let (size, unit) = format_size(transfer_size, config.size_units);
+                let (total, total_unit) = format_size(total, config.size_units);
 
-    let (size, unit) = format_size(total_transfer_size);
+                info!(
+                    "Transferred {:.1} {} at {} from backup file /{} ({:.1} {} in total)",
+                    size, unit, transfer.rate, transfer.file_name, total, total_unit
+                );
+            }
 
-    eprintln!("Transferred {size:.1} {unit} during restore.");
+            Ok(())
+        },
+    )?;
 
-    Ok(())
+    Ok((total_transfer_size, restored))
 }
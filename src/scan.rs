@@ -0,0 +1,148 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::backup::expand_dir;
+use super::{find_exclude, format_size, hidden_path, unfollowed_symlink, Config, Fallible};
+
+const LARGEST_FILES: usize = 10;
+
+pub fn scan(config: &Config, path: &Path) -> Fallible {
+    info!("Scanning {}...", path.display());
+
+    let mut paths = vec![(path.to_path_buf(), None, 0)];
+
+    let mut files = 0;
+    let mut dirs = 0;
+    let mut excluded = 0;
+    let mut permission_denied = 0;
+    let mut skipped_large_dirs = 0;
+    let mut total_size = 0;
+    let mut largest_files = Vec::<(u64, PathBuf)>::new();
+    let mut extensions = HashMap::<String, (usize, u64)>::new();
+
+    while let Some((path, root_dev, depth)) = paths.pop() {
+        if unfollowed_symlink(config, &path, depth) {
+            excluded += 1;
+            continue;
+        }
+
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(err) => {
+                warn!(
+                    "Skipping path {} as it appears to be a broken symbolic link: {}",
+                    path.display(),
+                    err
+                );
+                continue;
+            }
+        };
+
+        if find_exclude(config, &path).is_some() || hidden_path(config, &path, depth) {
+            excluded += 1;
+            continue;
+        }
+
+        if path.is_file() {
+            let size = match path.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(err) => {
+                    warn!(
+                        "Skipping file {} as it appears to have been removed: {}",
+                        path.display(),
+                        err
+                    );
+                    continue;
+                }
+            };
+
+            files += 1;
+            total_size += size;
+
+            let extension = path.extension().map_or_else(String::new, |extension| {
+                extension.to_string_lossy().to_lowercase()
+            });
+
+            let (count, bytes) = extensions.entry(extension).or_default();
+            *count += 1;
+            *bytes += size;
+
+            let pos = largest_files.partition_point(|(largest, _)| *largest >= size);
+
+            if pos < LARGEST_FILES {
+                largest_files.insert(pos, (size, path));
+                largest_files.truncate(LARGEST_FILES);
+            }
+        } else if path.is_dir() {
+            dirs += 1;
+
+            paths.extend(expand_dir(
+                config,
+                &path,
+                root_dev,
+                depth,
+                None,
+                &mut permission_denied,
+                &mut skipped_large_dirs,
+            ));
+        }
+    }
+
+    let (size, unit) = format_size(total_size, config.size_units);
+
+    println!("Files: {files}");
+    println!("Directories: {dirs}");
+    println!("Paths excluded: {excluded}");
+    println!("Paths not readable: {permission_denied}");
+    println!("Directories skipped due to their size: {skipped_large_dirs}");
+    println!("Total size: {size:.1} {unit}");
+
+    if !largest_files.is_empty() {
+        println!("\nLargest files:");
+
+        for (size, path) in &largest_files {
+            let (size, unit) = format_size(*size, config.size_units);
+
+            println!("{:>8.1} {:<3} {}", size, unit, path.display());
+        }
+    }
+
+    let mut extensions = extensions.into_iter().collect::<Vec<_>>();
+    extensions.sort_unstable_by_key(|(_, (_, bytes))| Reverse(*bytes));
+
+    if !extensions.is_empty() {
+        println!("\nBy extension:");
+
+        for (extension, (count, bytes)) in &extensions {
+            let (size, unit) = format_size(*bytes, config.size_units);
+            let extension = if extension.is_empty() {
+                "(none)"
+            } else {
+                extension
+            };
+
+            println!("{size:>8.1} {unit:<3} {count:>8} {extension}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::BTreeMap;
+use std::fs::create_dir_all;
+use std::mem::take;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{context, Config, Fallible, SubmountPolicy};
+
+// The create hook is expected to make each include root available read-only
+// at the same path below the snapshot root, e.g. /home at /mnt/snapshot/home.
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+pub struct SnapshotHooks {
+    root: PathBuf,
+    create: String,
+    remove: String,
+}
+
+// Backs up from the snapshot root as if it was the relative root, so that
+// archive paths and hence restores are the same as without snapshots.
+pub fn with_snapshots<F>(config: &mut Config, f: F) -> Fallible
+where
+    F: FnOnce(&Config) -> Fallible,
+{
+    let hooks = match config.snapshot.clone() {
+        Some(hooks) => hooks,
+        None => return f(config),
+    };
+
+    if config.metadata_manifest.is_some() {
+        return Err("Snapshots cannot be combined with a metadata manifest".into());
+    }
+
+    let mut snapshots = Vec::new();
+
+    let res = (|| {
+        for include in &config.includes {
+            let target = snapshot_path(&hooks.root, include);
+
+            if let Some(parent) = target.parent() {
+                create_dir_all(parent)?;
+            }
+
+            run_hook(config, &hooks.create, include, &target)
+                .map_err(context("Failed to create snapshot"))?;
+
+            snapshots.push((include.clone(), target));
+        }
+
+        let original = Paths::take(config);
+        original.snapshot(&hooks.root).put(config);
+
+        let res = f(config);
+
+        Paths::take(config);
+        original.put(config);
+
+        res
+    })();
+
+    for (include, target) in snapshots.iter().rev() {
+        if let Err(err) = run_hook(config, &hooks.remove, include, target) {
+            warn!(
+                "Failed to remove snapshot {} of {}: {}",
+                target.display(),
+                include.display(),
+                err
+            );
+        }
+    }
+
+    res
+}
+
+struct Paths {
+    relative_root: PathBuf,
+    includes: Vec<PathBuf>,
+    excludes: Vec<PathBuf>,
+    exclude_contents: Vec<PathBuf>,
+    root_excludes: Vec<(PathBuf, Vec<PathBuf>)>,
+    submounts: BTreeMap<PathBuf, SubmountPolicy>,
+}
+
+impl Paths {
+    fn take(config: &mut Config) -> Self {
+        Self {
+            relative_root: take(&mut config.relative_root),
+            includes: take(&mut config.includes),
+            excludes: take(&mut config.excludes),
+            exclude_contents: take(&mut config.exclude_contents),
+            root_excludes: take(&mut config.root_excludes),
+            submounts: take(&mut config.submounts),
+        }
+    }
+
+    fn put(&self, config: &mut Config) {
+        config.relative_root = self.relative_root.clone();
+        config.includes = self.includes.clone();
+        config.excludes = self.excludes.clone();
+        config.exclude_contents = self.exclude_contents.clone();
+        config.root_excludes = self.root_excludes.clone();
+        config.submounts = self.submounts.clone();
+    }
+
+    fn snapshot(&self, root: &Path) -> Self {
+        let map = |paths: &[PathBuf]| paths.iter().map(|path| snapshot_path(root, path)).collect();
+
+        Self {
+            relative_root: snapshot_path(root, &self.relative_root),
+            includes: map(&self.includes),
+            excludes: map(&self.excludes),
+            exclude_contents: map(&self.exclude_contents),
+            root_excludes: self
+                .root_excludes
+                .iter()
+                .map(|(include, excludes)| (snapshot_path(root, include), map(excludes)))
+                .collect(),
+            submounts: self
+                .submounts
+                .iter()
+                .map(|(path, policy)| (snapshot_path(root, path), *policy))
+                .collect(),
+        }
+    }
+}
+
+fn snapshot_path(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn run_hook(config: &Config, hook: &str, source: &Path, target: &Path) -> Fallible {
+    if config.print_args {
+        println!(
+            "SNAPSHOT_SOURCE={} SNAPSHOT_TARGET={} sh -c {:?}",
+            source.display(),
+            target.display(),
+            hook
+        );
+        return Ok(());
+    }
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("SNAPSHOT_SOURCE", source)
+        .env("SNAPSHOT_TARGET", target)
+        .status()?;
+
+    if !status.success() {
+        return Err(format!("Hook {hook:?} failed with status {status}").into());
+    }
+
+    Ok(())
+}
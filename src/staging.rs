@@ -0,0 +1,148 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::HashSet;
+use std::fs::{create_dir_all, rename};
+use std::path::{Path, PathBuf};
+
+use tempfile::{Builder, TempDir};
+
+use super::encryption::decrypt_in_place;
+use super::metadata::{restored_path, Manifest};
+use super::restore::Options;
+use super::{Config, Fallible};
+
+pub fn create_staging_dir(out_dir: &Path) -> Fallible<TempDir> {
+    create_dir_all(out_dir)?;
+
+    let staging_dir = Builder::new()
+        .prefix(".idrive_restore")
+        .tempdir_in(out_dir)?;
+
+    Ok(staging_dir)
+}
+
+pub fn needs_staging(config: &Config, opts: &Options) -> bool {
+    opts.atomic || opts.rewrites_paths() || config.client_encryption_key.is_some()
+}
+
+pub fn promote_staged_files(
+    config: &Config,
+    staging_dir: &Path,
+    out_dir: &Path,
+    opts: &Options,
+) -> Fallible {
+    let files = staged_files(staging_dir)?;
+
+    if let Some(key_file) = &config.client_encryption_key {
+        for path in &files {
+            decrypt_in_place(key_file, path)?;
+        }
+    }
+
+    let manifest = if opts.restore_metadata {
+        Manifest::load(config, &[staging_dir, out_dir])?
+    } else {
+        None
+    };
+
+    for path in files {
+        let archive_path = Path::new("/").join(path.strip_prefix(staging_dir)?);
+        let target = match opts.target_path(out_dir, &archive_path) {
+            Some(target) => target,
+            None => {
+                warn!(
+                    "Skipping file {} as no path components remain after stripping",
+                    archive_path.display()
+                );
+                continue;
+            }
+        };
+
+        if let Some(manifest) = &manifest {
+            if let Err(err) = manifest.apply(&archive_path, &path) {
+                warn!(
+                    "Failed to restore metadata of file {}: {}",
+                    target.display(),
+                    err
+                );
+            }
+        }
+
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+
+        rename(&path, &target)?;
+    }
+
+    if let Some(manifest) = &manifest {
+        manifest.restore_dirs(|path| opts.target_path(out_dir, path));
+        manifest.restore_symlinks(|path| opts.target_path(out_dir, path));
+        manifest.restore_dir_times(|path| opts.target_path(out_dir, path));
+    }
+
+    Ok(())
+}
+
+pub fn create_parent_dirs(
+    dest_dir: &Path,
+    items: &[PathBuf],
+    remote_dirs: &HashSet<PathBuf>,
+) -> Fallible {
+    create_dir_all(dest_dir)?;
+
+    for item in items {
+        let parent = match item.parent() {
+            Some(parent) if parent.has_root() && parent != Path::new("/") => parent,
+            _ => continue,
+        };
+
+        if !remote_dirs.contains(parent) {
+            warn!(
+                "Parent directory {} of item {} is missing from archive",
+                parent.display(),
+                item.display()
+            );
+        }
+
+        create_dir_all(restored_path(dest_dir, parent))?;
+    }
+
+    Ok(())
+}
+
+fn staged_files(staging_dir: &Path) -> Fallible<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![staging_dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in dir.read_dir()? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
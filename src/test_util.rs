@@ -0,0 +1,153 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+// Helpers shared by the unit tests of the subcommand flows.
+use std::ffi::OsString;
+use std::fs::{read_to_string, write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use tempfile::{NamedTempFile, TempDir};
+
+use super::{read_config, Config, Fallible, UtilProcess, UtilRunner, CONFIG_FILE};
+
+// Goes through reading the config file so that paths are resolved as they are at runtime.
+pub fn loaded_config(yaml: &str) -> Config {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join(CONFIG_FILE);
+    write(
+        &path,
+        format!("username: user\ndevice_name: device\nnotify_email: []\n{yaml}"),
+    )
+    .unwrap();
+
+    read_config(&path).unwrap()
+}
+
+pub fn config(yaml: &str) -> Config {
+    let config = format!(
+        r#"
+username: user
+device_name: device
+notify_email: []
+includes: []
+excludes: []
+{yaml}"#
+    );
+
+    serde_yaml::from_str(&config).unwrap()
+}
+
+// A single invocation of idevsutil_dedup as seen by the fake.
+pub struct Invocation {
+    pub args: Vec<String>,
+    // The lines of the file passed via `--files-from`, read before the invocation returns.
+    pub files_from: Vec<String>,
+}
+
+impl Invocation {
+    pub fn has_arg(&self, arg: &str) -> bool {
+        self.args.iter().any(|other| other == arg)
+    }
+
+    // The server path is the only argument referring to the account home.
+    pub fn server_path(&self) -> &str {
+        self.args
+            .iter()
+            .find(|arg| arg.contains("::home/"))
+            .map_or("", String::as_str)
+    }
+}
+
+type Respond = dyn Fn(&Invocation) -> (String, i32) + Send + Sync;
+
+// Answers each invocation with canned output and exit code instead of running idevsutil_dedup,
+// while recording the invocations for later inspection. Like the real thing, failed invocations
+// report their output on stderr.
+pub struct FakeUtil {
+    respond: Box<Respond>,
+    invocations: Mutex<Vec<Invocation>>,
+}
+
+impl FakeUtil {
+    pub fn install<F>(config: &mut Config, respond: F) -> Arc<Self>
+    where
+        F: Fn(&Invocation) -> (String, i32) + Send + Sync + 'static,
+    {
+        let fake = Arc::new(Self {
+            respond: Box::new(respond),
+            invocations: Mutex::new(Vec::new()),
+        });
+
+        config.util_runner = Some(fake.clone());
+
+        fake
+    }
+
+    pub fn invocations(&self) -> Vec<Invocation> {
+        std::mem::take(&mut self.invocations.lock().unwrap())
+    }
+}
+
+impl UtilRunner for FakeUtil {
+    fn spawn(
+        &self,
+        _config: &Config,
+        _key_index: usize,
+        args: &[OsString],
+    ) -> Fallible<UtilProcess> {
+        let args = args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        let files_from = match args
+            .iter()
+            .find_map(|arg| arg.strip_prefix("--files-from="))
+        {
+            Some(path) => read_to_string(path)?.lines().map(str::to_owned).collect(),
+            None => Vec::new(),
+        };
+
+        let invocation = Invocation { args, files_from };
+
+        let (output, code) = (self.respond)(&invocation);
+
+        self.invocations.lock().unwrap().push(invocation);
+
+        // A real process is spawned so that timeouts, exit codes and streaming are handled as usual.
+        let output_file = NamedTempFile::new()?;
+        write(&output_file, output)?;
+
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"if [ "$1" -eq 0 ]; then cat "$0"; else cat "$0" >&2; fi; exit "$1""#)
+            .arg(output_file.path())
+            .arg(code.to_string())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        Ok(UtilProcess {
+            child,
+            _pass_file: output_file,
+            _key_file: NamedTempFile::new()?,
+            _temp_dir: TempDir::new()?,
+        })
+    }
+}
@@ -0,0 +1,125 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::Config;
+
+#[derive(Default)]
+pub struct Timings {
+    entries: Mutex<BTreeMap<String, (usize, Duration)>>,
+}
+
+impl Timings {
+    pub fn record(&self, label: &str, elapsed: Duration) {
+        info!("Timing: {label} took {:.3} s", elapsed.as_secs_f64());
+
+        let mut entries = self.entries.lock().unwrap();
+        let (count, total) = entries.entry(label.to_owned()).or_default();
+
+        *count += 1;
+        *total += elapsed;
+    }
+
+    pub fn total(&self, label: &str) -> Duration {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(label)
+            .map_or(Duration::ZERO, |(_, total)| *total)
+    }
+
+    // Yields the total seconds per label.
+    pub fn totals(&self) -> BTreeMap<String, f64> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(label, (_, total))| (label.clone(), total.as_secs_f64()))
+            .collect()
+    }
+
+    pub fn report(&self) {
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(label, (count, total))| (label.clone(), *count, *total))
+            .collect::<Vec<_>>();
+
+        if entries.is_empty() {
+            return;
+        }
+
+        entries.sort_unstable_by_key(|(_, _, total)| Reverse(*total));
+
+        info!("Timing report:");
+
+        for (label, count, total) in entries {
+            info!(
+                "{:>10.3} s in {:>5} calls: {}",
+                total.as_secs_f64(),
+                count,
+                label
+            );
+        }
+    }
+}
+
+// Does not even read the clock when tracing is disabled.
+pub fn timed<T, F>(config: &Config, label: &str, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let timings = match &config.timings {
+        Some(timings) => timings,
+        None => return f(),
+    };
+
+    let start = Instant::now();
+    let res = f();
+    timings.record(label, start.elapsed());
+
+    res
+}
+
+// Names an idevsutil_dedup invocation by its operation, ignoring options common to all of them.
+pub fn util_label(args: &[OsString]) -> String {
+    let operation = args
+        .iter()
+        .map(OsString::as_os_str)
+        .filter(|arg| {
+            arg.to_str()
+                .is_some_and(|arg| arg.starts_with("--") && !arg.contains('='))
+        })
+        .find(|arg| {
+            !["--xml-output", "--checksum", "--type", "--relative"]
+                .map(OsStr::new)
+                .contains(arg)
+        });
+
+    match operation {
+        Some(operation) => format!("idevsutil_dedup {}", operation.to_string_lossy()),
+        None => "idevsutil_dedup transfer".to_owned(),
+    }
+}
@@ -0,0 +1,100 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::io::ErrorKind;
+use std::path::Path;
+
+use super::{context, local_path, walk_dir, Config, Fallible};
+
+// Checks the archived files against their local counterparts, i.e. the opposite direction of
+// `restore_missing`, by comparing the sizes reported by the listing with the local metadata.
+pub fn verify(config: &Config, srv_ip: &str, dev_id: &str, sub_dir: &Path) -> Fallible {
+    info!(
+        "Verifying backup of {} ({}) from {}...",
+        config.device_name, dev_id, srv_ip
+    );
+
+    // Encrypted files are archived with a different size than their plaintext.
+    let compare_sizes = config.client_encryption_key.is_none();
+
+    if !compare_sizes {
+        warn!("Not comparing sizes as files are encrypted before upload");
+    }
+
+    let mut matched = 0;
+    let mut missing = 0;
+    let mut mismatched = 0;
+
+    walk_dir(config, srv_ip, dev_id, sub_dir, |path, size, _| {
+        let remote_size = match size {
+            Some(remote_size) => remote_size,
+            None => return Ok(Some(path)),
+        };
+
+        let local_path = local_path(config, &path);
+
+        match local_path.metadata() {
+            Ok(metadata) if compare_sizes && metadata.len() != remote_size => {
+                println!(
+                    "Size of file {} differs: {} bytes archived, {} bytes local",
+                    path.display(),
+                    remote_size,
+                    metadata.len()
+                );
+
+                mismatched += 1;
+            }
+            Ok(_) => matched += 1,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                println!("File {} is missing locally", path.display());
+
+                missing += 1;
+            }
+            Err(err) => {
+                println!(
+                    "File {} could not be checked: {}",
+                    local_path.display(),
+                    err
+                );
+
+                mismatched += 1;
+            }
+        }
+
+        Ok(Some(path))
+    })
+    .map_err(context("Failed to walk remote directory"))?;
+
+    if config.print_args {
+        return Ok(());
+    }
+
+    info!(
+        "Verified {} files: {} matched, {} missing locally, {} mismatched",
+        matched + missing + mismatched,
+        matched,
+        missing,
+        mismatched
+    );
+
+    if mismatched != 0 {
+        return Err(format!("{mismatched} files differ between archive and local copy").into());
+    }
+
+    Ok(())
+}
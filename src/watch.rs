@@ -0,0 +1,244 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr::read_unaligned;
+use std::time::{Duration, Instant};
+
+use super::backup::backup_files;
+use super::{archive_path, find_exclude, Config, Fallible};
+
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+
+// Changed files are collected until no further changes were observed for the debounce interval,
+// then they are uploaded as a single incremental backup. Directories created while watching are
+// watched as well and their files are considered changed.
+pub fn watch(config: &Config, srv_ip: &str, dev_id: &str, debounce: Duration) -> Fallible {
+    let mut inotify = Inotify::new()?;
+
+    for include in &config.includes {
+        let include = include.canonicalize()?;
+
+        inotify.add_tree(config, &include, &mut BTreeSet::new());
+    }
+
+    info!("Watching {} directories for changes...", inotify.dirs.len());
+
+    let mut changed = BTreeSet::new();
+    let mut last_change = Instant::now();
+
+    loop {
+        let timeout = if changed.is_empty() {
+            None
+        } else {
+            Some(debounce.saturating_sub(last_change.elapsed()))
+        };
+
+        if inotify.read_events(config, timeout, &mut changed)? {
+            last_change = Instant::now();
+            continue;
+        }
+
+        if changed.is_empty() {
+            continue;
+        }
+
+        let files = changed
+            .iter()
+            .filter(|path| path.is_file())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        changed.clear();
+
+        if files.is_empty() {
+            continue;
+        }
+
+        info!("Backing up {} changed files...", files.len());
+
+        if let Err(err) = backup_files(config, srv_ip, dev_id, &files) {
+            error!("Failed to backup changed files: {err}");
+        }
+    }
+}
+
+struct Inotify {
+    fd: libc::c_int,
+    dirs: HashMap<libc::c_int, PathBuf>,
+}
+
+impl Inotify {
+    fn new() -> Fallible<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Self {
+            fd,
+            dirs: HashMap::new(),
+        })
+    }
+
+    // Yields the files found below the given directory so that those created
+    // before the watch was established are not missed.
+    fn add_tree(&mut self, config: &Config, dir: &Path, files: &mut BTreeSet<PathBuf>) {
+        let mut dirs = vec![dir.to_path_buf()];
+
+        while let Some(dir) = dirs.pop() {
+            if find_exclude(config, &dir).is_some() || archive_path(config, &dir).is_none() {
+                continue;
+            }
+
+            let path = match CString::new(dir.as_os_str().as_bytes()) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+
+            let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK) };
+
+            if wd < 0 {
+                warn!(
+                    "Failed to watch directory {}: {}",
+                    dir.display(),
+                    io::Error::last_os_error()
+                );
+                continue;
+            }
+
+            let entries = match dir.read_dir() {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warn!("Failed to read directory {}: {}", dir.display(), err);
+                    continue;
+                }
+            };
+
+            for entry in entries.flatten() {
+                match entry.file_type() {
+                    Ok(file_type) if file_type.is_dir() => dirs.push(entry.path()),
+                    Ok(file_type) if file_type.is_file() => {
+                        files.insert(entry.path());
+                    }
+                    _ => (),
+                }
+            }
+
+            self.dirs.insert(wd, dir);
+        }
+    }
+
+    // Returns whether any event was read before the timeout elapsed.
+    fn read_events(
+        &mut self,
+        config: &Config,
+        timeout: Option<Duration>,
+        changed: &mut BTreeSet<PathBuf>,
+    ) -> Fallible<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int
+        });
+
+        let res = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+
+        if res < 0 {
+            let err = io::Error::last_os_error();
+
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+
+            return Err(err.into());
+        }
+
+        if res == 0 {
+            return Ok(false);
+        }
+
+        let mut buf = [0_u8; 64 * 1024];
+
+        let len = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) };
+
+        if len < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let buf = &buf[..len as usize];
+        let mut pos = 0;
+
+        while pos + size_of::<libc::inotify_event>() <= buf.len() {
+            let event =
+                unsafe { read_unaligned(buf[pos..].as_ptr().cast::<libc::inotify_event>()) };
+
+            let name_start = pos + size_of::<libc::inotify_event>();
+            let name_end = (name_start + event.len as usize).min(buf.len());
+            pos = name_end;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                warn!("WARNING: Changes were lost as the event queue overflowed!");
+                continue;
+            }
+
+            let name = &buf[name_start..name_end];
+            let name = &name[..name
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(name.len())];
+
+            let path = match self.dirs.get(&event.wd) {
+                Some(dir) if !name.is_empty() => dir.join(OsStr::from_bytes(name)),
+                _ => continue,
+            };
+
+            if find_exclude(config, &path).is_some() {
+                continue;
+            }
+
+            if event.mask & libc::IN_ISDIR != 0 {
+                if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    self.add_tree(config, &path, changed);
+                }
+            } else if event.mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 {
+                changed.insert(path);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::ffi::{CString, OsStr, OsString};
+use std::io::Error as IoError;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::ptr::null_mut;
+
+use super::Fallible;
+
+pub fn read_xattrs(path: &Path) -> Fallible<Vec<(OsString, Vec<u8>)>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+
+    let names = read_buf(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf.cast(), len) })?;
+
+    let mut xattrs = Vec::new();
+
+    for name in names
+        .split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let c_name = CString::new(name)?;
+
+        let value = read_buf(|buf, len| unsafe {
+            libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), buf.cast(), len)
+        })?;
+
+        xattrs.push((OsString::from_vec(name.to_vec()), value));
+    }
+
+    Ok(xattrs)
+}
+
+pub fn write_xattr(path: &Path, name: &OsStr, value: &[u8]) -> Fallible {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name.as_bytes())?;
+
+    let res = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+
+    if res != 0 {
+        return Err(IoError::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+// Queries the required size first and retries if the value grew in between.
+fn read_buf<F>(mut f: F) -> Fallible<Vec<u8>>
+where
+    F: FnMut(*mut u8, usize) -> libc::ssize_t,
+{
+    loop {
+        let len = f(null_mut(), 0);
+
+        if len < 0 {
+            return Err(IoError::last_os_error().into());
+        }
+
+        let mut buf = vec![0; len as usize];
+
+        let len = f(buf.as_mut_ptr(), buf.len());
+
+        if len < 0 {
+            let err = IoError::last_os_error();
+
+            if err.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+
+            return Err(err.into());
+        }
+
+        buf.truncate(len as usize);
+
+        return Ok(buf);
+    }
+}
@@ -0,0 +1,492 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::Fallible;
+
+// Extracts a single entry from a ZIP archive held in memory, supporting the stored and deflate
+// methods and verifying the CRC-32 of the extracted contents. ZIP64 archives are not supported.
+pub fn extract_entry(archive: &[u8], name: &str) -> Fallible<Vec<u8>> {
+    let eocd = find_end_of_central_dir(archive)?;
+
+    let entries = read_u16(archive, eocd + 10)?;
+    let mut offset = read_u32(archive, eocd + 16)? as usize;
+
+    for _ in 0..entries {
+        if read_u32(archive, offset)? != 0x02014b50 {
+            return Err("Invalid central directory header".into());
+        }
+
+        let method = read_u16(archive, offset + 10)?;
+        let crc = read_u32(archive, offset + 16)?;
+        let compressed_size = read_u32(archive, offset + 20)? as usize;
+        let uncompressed_size = read_u32(archive, offset + 24)? as usize;
+        let name_len = read_u16(archive, offset + 28)? as usize;
+        let extra_len = read_u16(archive, offset + 30)? as usize;
+        let comment_len = read_u16(archive, offset + 32)? as usize;
+        let local_header = read_u32(archive, offset + 42)? as usize;
+
+        let entry_name = slice(archive, offset + 46, name_len)?;
+
+        offset += 46 + name_len + extra_len + comment_len;
+
+        if entry_name != name.as_bytes() {
+            continue;
+        }
+
+        if read_u32(archive, local_header)? != 0x04034b50 {
+            return Err("Invalid local file header".into());
+        }
+
+        let data_offset = local_header
+            + 30
+            + read_u16(archive, local_header + 26)? as usize
+            + read_u16(archive, local_header + 28)? as usize;
+
+        let data = slice(archive, data_offset, compressed_size)?;
+
+        let contents = match method {
+            0 => data.to_vec(),
+            8 => inflate(data, uncompressed_size)?,
+            method => return Err(format!("Unsupported compression method {method}").into()),
+        };
+
+        if contents.len() != uncompressed_size || crc32(&contents) != crc {
+            return Err(format!("Entry {name} failed integrity check").into());
+        }
+
+        return Ok(contents);
+    }
+
+    Err(format!("Archive does not contain {name}").into())
+}
+
+fn find_end_of_central_dir(archive: &[u8]) -> Fallible<usize> {
+    // The record is followed by a comment of at most 65535 bytes.
+    let end = archive
+        .len()
+        .checked_sub(22)
+        .ok_or("Archive is truncated")?;
+    let start = end.saturating_sub(u16::MAX as usize);
+
+    (start..=end)
+        .rev()
+        .find(|&offset| archive[offset..offset + 4] == [0x50, 0x4b, 0x05, 0x06])
+        .ok_or_else(|| "Did not find end of central directory".into())
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Fallible<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| "Archive is truncated".into())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Fallible<u16> {
+    let bytes = slice(data, offset, 2)?;
+
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Fallible<u32> {
+    let bytes = slice(data, offset, 4)?;
+
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0_u32; 256];
+
+    for (idx, entry) in table.iter_mut().enumerate() {
+        let mut crc = idx as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+
+        *entry = crc;
+    }
+
+    !data.iter().fold(!0, |crc, &byte| {
+        table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+// Decompresses a raw DEFLATE stream as specified by RFC 1951, stopping as soon as the output
+// would exceed the given size so that a corrupt or malicious entry cannot exhaust memory.
+fn inflate(data: &[u8], max_size: usize) -> Fallible<Vec<u8>> {
+    let mut bits = Bits {
+        data,
+        pos: 0,
+        buf: 0,
+        cnt: 0,
+    };
+    // DEFLATE cannot expand data by more than a factor of about a thousand.
+    let mut out = Vec::with_capacity(max_size.min(data.len().saturating_mul(1032)));
+
+    loop {
+        let last = bits.read(1)? != 0;
+
+        match bits.read(2)? {
+            0 => {
+                bits.align();
+
+                let len = bits.read(16)? as usize;
+                let nlen = bits.read(16)? as usize;
+
+                if len != !nlen & 0xffff {
+                    return Err("Invalid length of stored block".into());
+                }
+
+                check_size(&out, len, max_size)?;
+
+                out.extend_from_slice(slice(data, bits.pos, len)?);
+                bits.pos += len;
+            }
+            1 => {
+                let mut lengths = [0; 288 + 30];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..288].fill(8);
+                lengths[288..].fill(5);
+
+                let lit = Huffman::new(&lengths[..288]);
+                let dist = Huffman::new(&lengths[288..]);
+
+                inflate_block(&mut bits, &mut out, max_size, &lit, &dist)?;
+            }
+            2 => {
+                let (lit, dist) = read_dynamic_codes(&mut bits)?;
+
+                inflate_block(&mut bits, &mut out, max_size, &lit, &dist)?;
+            }
+            _ => return Err("Invalid block type".into()),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn read_dynamic_codes(bits: &mut Bits) -> Fallible<(Huffman, Huffman)> {
+    let nlit = bits.read(5)? as usize + 257;
+    let ndist = bits.read(5)? as usize + 1;
+    let ncode = bits.read(4)? as usize + 4;
+
+    if nlit > 286 || ndist > 30 {
+        return Err("Invalid number of codes".into());
+    }
+
+    let mut code_lengths = [0; 19];
+    for &idx in &CODE_LENGTH_ORDER[..ncode] {
+        code_lengths[idx] = bits.read(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = vec![0; nlit + ndist];
+    let mut idx = 0;
+
+    while idx < lengths.len() {
+        let (len, repeat) = match code_lengths.decode(bits)? {
+            sym @ 0..=15 => (sym as u8, 1),
+            16 => {
+                let prev = *lengths[..idx]
+                    .last()
+                    .ok_or("Repeated code length without predecessor")?;
+
+                (prev, 3 + bits.read(2)? as usize)
+            }
+            17 => (0, 3 + bits.read(3)? as usize),
+            _ => (0, 11 + bits.read(7)? as usize),
+        };
+
+        lengths
+            .get_mut(idx..idx + repeat)
+            .ok_or("Too many code lengths")?
+            .fill(len);
+        idx += repeat;
+    }
+
+    if lengths[256] == 0 {
+        return Err("Missing end-of-block code".into());
+    }
+
+    Ok((
+        Huffman::new(&lengths[..nlit]),
+        Huffman::new(&lengths[nlit..]),
+    ))
+}
+
+fn check_size(out: &[u8], len: usize, max_size: usize) -> Fallible {
+    if out.len() + len > max_size {
+        return Err("Decompressed data exceeds expected size".into());
+    }
+
+    Ok(())
+}
+
+fn inflate_block(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    max_size: usize,
+    lit: &Huffman,
+    dist: &Huffman,
+) -> Fallible {
+    loop {
+        let sym = lit.decode(bits)? as usize;
+
+        if sym < 256 {
+            check_size(out, 1, max_size)?;
+
+            out.push(sym as u8);
+            continue;
+        }
+
+        if sym == 256 {
+            return Ok(());
+        }
+
+        let sym = sym - 257;
+        if sym >= LENGTH_BASE.len() {
+            return Err("Invalid length symbol".into());
+        }
+        let len = LENGTH_BASE[sym] as usize + bits.read(LENGTH_EXTRA[sym])? as usize;
+
+        let sym = dist.decode(bits)? as usize;
+        if sym >= DIST_BASE.len() {
+            return Err("Invalid distance symbol".into());
+        }
+        let distance = DIST_BASE[sym] as usize + bits.read(DIST_EXTRA[sym])? as usize;
+
+        let start = out
+            .len()
+            .checked_sub(distance)
+            .ok_or("Distance exceeds output")?;
+
+        check_size(out, len, max_size)?;
+
+        // Copies byte by byte as source and destination may overlap.
+        for idx in start..start + len {
+            out.push(out[idx]);
+        }
+    }
+}
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    cnt: u8,
+}
+
+impl Bits<'_> {
+    fn read(&mut self, cnt: u8) -> Fallible<u32> {
+        while self.cnt < cnt {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or("Compressed data is truncated")?;
+            self.pos += 1;
+
+            self.buf |= (byte as u32) << self.cnt;
+            self.cnt += 8;
+        }
+
+        let val = self.buf & ((1 << cnt) - 1);
+        self.buf >>= cnt;
+        self.cnt -= cnt;
+
+        Ok(val)
+    }
+
+    fn align(&mut self) {
+        self.buf = 0;
+        self.cnt = 0;
+    }
+}
+
+// Canonical Huffman code represented by the number of codes per length and the symbols ordered by code.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = sym as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Fallible<u16> {
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+
+        for &count in &self.counts[1..] {
+            let count = count as usize;
+
+            code |= bits.read(1)? as usize;
+
+            if code < first + count {
+                return Ok(self.symbols[index + code - first]);
+            }
+
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+
+        Err("Invalid Huffman code".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN: &[u8] = b"hello hello hello hello world";
+
+    fn lines() -> Vec<u8> {
+        (0..60)
+            .flat_map(|idx| format!("line {idx}\n").into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn inflate_stored_block() {
+        let data = include_bytes!("../testdata/stored.deflate");
+
+        assert_eq!(inflate(data, PLAIN.len()).unwrap(), PLAIN);
+    }
+
+    #[test]
+    fn inflate_fixed_huffman_block() {
+        let data = include_bytes!("../testdata/fixed.deflate");
+
+        assert_eq!(inflate(data, PLAIN.len()).unwrap(), PLAIN);
+    }
+
+    #[test]
+    fn inflate_dynamic_huffman_block() {
+        let data = include_bytes!("../testdata/dynamic.deflate");
+        let lines = lines();
+
+        assert_eq!(inflate(data, lines.len()).unwrap(), lines);
+    }
+
+    #[test]
+    fn inflate_stops_at_maximum_size() {
+        let stored = include_bytes!("../testdata/stored.deflate");
+        assert!(inflate(stored, PLAIN.len() - 1).is_err());
+
+        let fixed = include_bytes!("../testdata/fixed.deflate");
+        assert!(inflate(fixed, PLAIN.len() - 1).is_err());
+
+        let dynamic = include_bytes!("../testdata/dynamic.deflate");
+        assert!(inflate(dynamic, 10).is_err());
+    }
+
+    #[test]
+    fn inflate_rejects_truncated_data() {
+        let data = include_bytes!("../testdata/dynamic.deflate");
+
+        assert!(inflate(&data[..data.len() / 2], usize::MAX).is_err());
+    }
+
+    #[test]
+    fn extract_stored_and_deflated_entries() {
+        let archive = include_bytes!("../testdata/archive.zip");
+
+        assert_eq!(extract_entry(archive, "stored.txt").unwrap(), PLAIN);
+        assert_eq!(extract_entry(archive, "deflated.txt").unwrap(), lines());
+    }
+
+    #[test]
+    fn extract_missing_entry() {
+        let archive = include_bytes!("../testdata/archive.zip");
+
+        assert!(extract_entry(archive, "missing.txt").is_err());
+    }
+
+    #[test]
+    fn extract_from_truncated_archive() {
+        let archive = include_bytes!("../testdata/archive.zip");
+
+        assert!(extract_entry(&archive[..archive.len() - 30], "stored.txt").is_err());
+        assert!(extract_entry(&archive[..100], "deflated.txt").is_err());
+        assert!(extract_entry(&[], "stored.txt").is_err());
+    }
+
+    #[test]
+    fn extract_detects_corruption() {
+        let mut archive = include_bytes!("../testdata/archive.zip").to_vec();
+
+        // Flips a byte of the stored contents following the local header and name.
+        archive[30 + "stored.txt".len()] ^= 0xff;
+
+        assert!(extract_entry(&archive, "stored.txt").is_err());
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+}